@@ -0,0 +1,29 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome};
+use rocket::Request;
+
+use crate::settings::Settings;
+
+/// Guard for maintenance endpoints. Requests need "Authorization: Bearer <server.admin_token>", and
+/// with no token set the endpoints are off altogether
+pub struct Admin;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Admin {
+    type Error = ();
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Admin, ()> {
+        let token = match request.rocket().state::<Settings>().and_then(|s| s.server.admin_token.as_ref()) {
+            Some(token) => token,
+            None => return Outcome::Forward(Status::NotFound),
+        };
+
+        let given = request.headers().get_one("Authorization").and_then(|x| x.strip_prefix("Bearer "));
+
+        if given == Some(token.as_str()) {
+            Outcome::Success(Admin)
+        } else {
+            Outcome::Error((Status::Unauthorized, ()))
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use anyhow::Error;
+use opencv::core::{no_array, Size, CMP_GT, CMP_LT, CV_8U};
+use opencv::imgproc::{INTER_LINEAR, INTER_NEAREST};
+use opencv::prelude::{Mat, MatTraitConst};
+use serde::Serialize;
+
+use crate::filters::index::{valid_mask, Index};
+use crate::filters::simple_composite;
+use crate::sat_data::SatData;
+
+/// Area of each change class in km²
+#[derive(Debug, Clone, Serialize)]
+pub struct ChangeStats {
+    pub decrease_km2: f64,
+    pub increase_km2: f64,
+    pub unchanged_km2: f64,
+}
+
+pub struct Change {
+    /// After minus before, as 32 bit floats
+    pub difference: Mat,
+    /// 255 where the index dropped by more than the threshold
+    pub decrease: Mat,
+    /// 255 where the index rose by more than the threshold
+    pub increase: Mat,
+    pub stats: ChangeStats,
+}
+
+fn resize(m: Mat, size: Size, interpolation: i32) -> Mat {
+    if m.size().unwrap() == size {
+        return m;
+    }
+
+    let mut to_return = Mat::default();
+    opencv::imgproc::resize(&m, &mut to_return, size, 0.0, 0.0, interpolation).unwrap();
+
+    to_return
+}
+
+fn and(a: &Mat, b: &Mat) -> Mat {
+    let mut to_return = Mat::default();
+    opencv::core::bitwise_and(a, b, &mut to_return, &no_array()).unwrap();
+
+    to_return
+}
+
+/// Compare an index between two acquisitions of the same tile. Both products are put on the grid
+/// of the before product, and only pixels imaged both times are counted. A pixel has changed when
+/// the index moved by more than the threshold.
+pub fn detect_change(before: &SatData, after: &SatData, index: Index, threshold: f64) -> anyhow::Result<Change> {
+    // we can only compare pixels that line up
+    if let (Some(b), Some(a)) = (before.metadata(), after.metadata()) {
+        if b.tile_id != a.tile_id {
+            return Err(Error::msg(format!("Products are on different tiles ({} and {})", b.tile_id, a.tile_id)));
+        }
+    }
+
+    let before_index = index.compute(before);
+    let size = before_index.size().unwrap();
+    let after_index = resize(index.compute(after), size, INTER_LINEAR);
+
+    let valid = and(
+        &resize(valid_mask(before), size, INTER_NEAREST),
+        &resize(valid_mask(after), size, INTER_NEAREST),
+    );
+
+    let mut difference = Mat::default();
+    opencv::core::subtract(&after_index, &before_index, &mut difference, &no_array(), -1).unwrap();
+
+    let mut decrease = Mat::default();
+    let mut increase = Mat::default();
+    opencv::core::compare(&difference, &(-threshold), &mut decrease, CMP_LT).unwrap();
+    opencv::core::compare(&difference, &threshold, &mut increase, CMP_GT).unwrap();
+
+    let decrease = and(&decrease, &valid);
+    let increase = and(&increase, &valid);
+
+    // default to the 10 m pixel size if the metadata is missing
+    let pixel_area = before.metadata()
+        .map(|m| m.pixel_area_km2(size.width))
+        .unwrap_or(0.0001);
+
+    let valid_count = opencv::core::count_non_zero(&valid).unwrap() as f64;
+    let decrease_count = opencv::core::count_non_zero(&decrease).unwrap() as f64;
+    let increase_count = opencv::core::count_non_zero(&increase).unwrap() as f64;
+
+    Ok(Change {
+        difference,
+        decrease,
+        increase,
+        stats: ChangeStats {
+            decrease_km2: decrease_count * pixel_area,
+            increase_km2: increase_count * pixel_area,
+            unchanged_km2: (valid_count - decrease_count - increase_count) * pixel_area,
+        },
+    })
+}
+
+impl Change {
+    /// Difference map with drops in red and rises in green. The bigger the change the brighter
+    pub fn render_difference(&self) -> Mat {
+        let mut drops = Mat::default();
+        let mut rises = Mat::default();
+
+        // converting to u8 clips the other sign to zero
+        self.difference.convert_to(&mut drops, CV_8U, -255.0, 0.0).unwrap();
+        self.difference.convert_to(&mut rises, CV_8U, 255.0, 0.0).unwrap();
+
+        let mut empty = Mat::default();
+        opencv::core::multiply(&drops, &0.0, &mut empty, 1.0, -1).unwrap();
+
+        simple_composite(drops, rises, empty)
+    }
+
+    /// Change mask with drops in red and rises in blue
+    pub fn render_mask(&self) -> Mat {
+        let mut empty = Mat::default();
+        opencv::core::multiply(&self.decrease, &0.0, &mut empty, 1.0, -1).unwrap();
+
+        simple_composite(self.decrease.clone(), empty, self.increase.clone())
+    }
+}
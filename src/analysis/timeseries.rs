@@ -0,0 +1,87 @@
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+
+use crate::analysis::zonal::{zonal_stats, Layer, Zone};
+use crate::cdse::search_result::SearchResult;
+use crate::cdse::CDSE;
+use crate::geo::Polygon;
+
+/// Statistics of every zone for one acquisition
+#[derive(Debug, Clone, Serialize)]
+pub struct SeriesEntry {
+    pub date: String,
+    pub id: String,
+    pub zones: Vec<Zone>,
+}
+
+/// Run zonal statistics over every product, oldest first. Clouds are masked out, so a zone fully
+/// under cloud has no stats for that date. Offline products, radar products and products that fail
+/// to process are skipped.
+pub async fn time_series(cdse: &CDSE, mut products: Vec<SearchResult>, layer: &Layer, polygons: &[Polygon], percentiles: &[f64]) -> Vec<SeriesEntry> {
+    // search gives newest first
+    products.reverse();
+
+    let mut series = Vec::with_capacity(products.len());
+
+    for product in products.into_iter().filter(|x| x.online) {
+        // zips are cached in the bucket, so products we have seen before are quick
+        let sat_data = match cdse.sat_data(product.id.as_str()).await {
+            Ok(sat_data) if sat_data.sar().is_some() => {
+                warn!("Skipping {} in the time series: radar products have no optical bands", product.id);
+                continue;
+            }
+            Ok(sat_data) => sat_data,
+            Err(e) => {
+                warn!("Skipping {} in the time series: {e}", product.id);
+                continue;
+            }
+        };
+
+        let (stats_data, layer, polygons, percentiles) = (sat_data.clone(), layer.clone(), polygons.to_vec(), percentiles.to_vec());
+        let zones = spawn_blocking(move || zonal_stats(&stats_data, &layer, &polygons, &percentiles, true)).await.unwrap();
+
+        if let Ok(zones) = zones {
+            // prefer the tile's own sensing time over the catalogue's
+            let date = sat_data.metadata()
+                .map(|m| m.sensing_time.clone())
+                .unwrap_or(product.date);
+
+            series.push(SeriesEntry { date, id: product.id, zones });
+        }
+    }
+
+    series
+}
+
+/// Flatten a series to CSV with one row per date and zone. Zones are numbered in the order they
+/// were given. Zones without stats get empty columns
+pub fn to_csv(series: &[SeriesEntry], percentiles: &[f64]) -> String {
+    let mut csv = String::from("date,id,zone,pixels,area_km2,mean,median,min,max,stddev");
+    for p in percentiles {
+        csv.push_str(format!(",p{p}").as_str());
+    }
+    csv.push('\n');
+
+    for entry in series {
+        for (zone_number, zone) in entry.zones.iter().enumerate() {
+            csv.push_str(format!("{},{},{}", entry.date, entry.id, zone_number).as_str());
+
+            if let Some(stats) = &zone.stats {
+                csv.push_str(format!(
+                    ",{},{},{},{},{},{},{}",
+                    stats.pixels, stats.area_km2, stats.mean, stats.median, stats.min, stats.max, stats.stddev
+                ).as_str());
+
+                for p in percentiles {
+                    csv.push_str(format!(",{}", stats.percentiles[&format!("p{p}")]).as_str());
+                }
+            } else {
+                csv.push_str(",".repeat(7 + percentiles.len()).as_str());
+            }
+
+            csv.push('\n');
+        }
+    }
+
+    csv
+}
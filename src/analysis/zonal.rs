@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use opencv::core::{no_array, Point, Scalar, Size, CV_8U};
+use opencv::imgproc::LINE_8;
+use opencv::prelude::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual};
+use opencv::types::{VectorOfPoint, VectorOfVectorOfPoint};
+use serde::Serialize;
+
+use crate::filters::expression::Expression;
+use crate::filters::classify::clear_mask;
+use crate::filters::index::{valid_mask, Index};
+use crate::geo::Polygon;
+use crate::metadata::TileMetadata;
+use crate::sat_data::SatData;
+
+/// What to measure over a zone: one of the named indexes or a band math expression
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layer {
+    Index(Index),
+    Expression(Expression),
+}
+
+impl Layer {
+    /// Read the layer from the "Index" or "Expression" key of a request
+    pub fn from_json(data: &serde_json::Value) -> anyhow::Result<Layer> {
+        if let Some(expression) = data["Expression"].as_str() {
+            Ok(Layer::Expression(Expression::parse(expression)?))
+        } else {
+            let name = data["Index"].as_str().unwrap_or("NDVI");
+
+            Index::from_name(name)
+                .map(Layer::Index)
+                .ok_or_else(|| Error::msg(format!("Unknown index {name}")))
+        }
+    }
+
+    pub fn compute(&self, data: &SatData) -> anyhow::Result<Mat> {
+        match self {
+            Layer::Index(index) => Ok(index.compute(data)),
+            Layer::Expression(expression) => expression.evaluate(data),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ZoneStats {
+    pub pixels: usize,
+    pub area_km2: f64,
+    pub mean: f64,
+    pub median: f64,
+    pub min: f64,
+    pub max: f64,
+    pub stddev: f64,
+    /// Keyed like "p10" for the 10th percentile
+    pub percentiles: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Zone {
+    /// Properties of the GeoJSON feature, so callers can tell their zones apart
+    pub properties: serde_json::Value,
+    /// None when the polygon does not cover any imaged pixel of the product
+    pub stats: Option<ZoneStats>,
+}
+
+/// Draw a polygon onto an empty mask of the given size (255 inside). Holes are left out
+pub fn rasterize(polygon: &Polygon, metadata: &TileMetadata, size: Size) -> Option<Mat> {
+    let mut mask = Mat::new_size_with_default(size, CV_8U, Scalar::all(0.0)).unwrap();
+
+    if polygon.is_point() {
+        let (lon, lat) = polygon.rings[0][0];
+        let (column, row) = metadata.to_pixel(lon, lat, size.width)?;
+
+        // leave the mask empty if the point is off the tile
+        if let Ok(pixel) = mask.at_2d_mut::<u8>(row.floor() as i32, column.floor() as i32) {
+            *pixel = 255;
+        }
+
+        return Some(mask);
+    }
+
+    let mut rings = VectorOfVectorOfPoint::new();
+
+    for ring in &polygon.rings {
+        let mut points = VectorOfPoint::new();
+
+        for (lon, lat) in ring {
+            let (column, row) = metadata.to_pixel(*lon, *lat, size.width)?;
+            points.push(Point::new(column.round() as i32, row.round() as i32));
+        }
+
+        rings.push(points);
+    }
+
+    // filling all rings together makes the inner ones holes
+    opencv::imgproc::fill_poly(&mut mask, &rings, Scalar::all(255.0), LINE_8, 0, Point::default()).unwrap();
+
+    Some(mask)
+}
+
+/// Value at the given percent of an already sorted list, interpolating between neighbours
+fn percentile(sorted: &[f32], percent: f64) -> f64 {
+    let position = (sorted.len() - 1) as f64 * percent / 100.0;
+    let low = position.floor() as usize;
+    let high = position.ceil() as usize;
+
+    let fraction = position - low as f64;
+
+    sorted[low] as f64 * (1.0 - fraction) + sorted[high] as f64 * fraction
+}
+
+/// Statistics of a 32 bit float layer over the pixels set in the mask
+pub fn zone_stats(values: &Mat, mask: &Mat, pixel_area_km2: f64, percentiles: &[f64]) -> Option<ZoneStats> {
+    let mut inside: Vec<f32> = values.data_typed::<f32>().unwrap()
+        .iter()
+        .zip(mask.data_bytes().unwrap())
+        .filter(|(value, m)| **m != 0 && value.is_finite())
+        .map(|(value, _)| *value)
+        .collect();
+
+    if inside.is_empty() {
+        return None;
+    }
+
+    inside.sort_by(|a, b| a.total_cmp(b));
+
+    let count = inside.len() as f64;
+    let mean = inside.iter().map(|x| *x as f64).sum::<f64>() / count;
+    let variance = inside.iter().map(|x| (*x as f64 - mean).powi(2)).sum::<f64>() / count;
+
+    Some(ZoneStats {
+        pixels: inside.len(),
+        area_km2: count * pixel_area_km2,
+        mean,
+        median: percentile(&inside, 50.0),
+        min: inside[0] as f64,
+        max: inside[inside.len() - 1] as f64,
+        stddev: variance.sqrt(),
+        percentiles: percentiles.iter()
+            .map(|p| (format!("p{p}"), percentile(&inside, p.clamp(0.0, 100.0))))
+            .collect(),
+    })
+}
+
+/// Statistics of a layer over every polygon. Pixels outside the satellite's swath are ignored, and
+/// so are clouds when mask_clouds is set
+pub fn zonal_stats(data: &SatData, layer: &Layer, polygons: &[Polygon], percentiles: &[f64], mask_clouds: bool) -> anyhow::Result<Vec<Zone>> {
+    let metadata = data.metadata().ok_or_else(|| Error::msg("Product has no tile metadata to place the polygons"))?;
+
+    let values = layer.compute(data)?;
+    let size = values.size().unwrap();
+    let valid = if mask_clouds { clear_mask(data) } else { valid_mask(data) };
+
+    let mut zones = Vec::with_capacity(polygons.len());
+
+    for polygon in polygons {
+        let stats = rasterize(polygon, metadata, size).and_then(|mask| {
+            let mut inside = Mat::default();
+            opencv::core::bitwise_and(&mask, &valid, &mut inside, &no_array()).unwrap();
+
+            zone_stats(&values, &inside, metadata.pixel_area_km2(size.width), percentiles)
+        });
+
+        zones.push(Zone {
+            properties: polygon.properties.clone(),
+            stats,
+        });
+    }
+
+    Ok(zones)
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+
+use crate::settings::DiskCacheSettings;
+
+/// Extension of files still being written, so a crash never leaves half a file in the cache
+const PARTIAL: &str = "part";
+
+struct Entry {
+    size: u64,
+    written: SystemTime,
+    last_used: SystemTime,
+}
+
+#[derive(Default)]
+struct Index {
+    entries: HashMap<String, Entry>,
+    total: u64,
+}
+
+/// Files kept on local disk under the same names as in the bucket. Least recently used files are
+/// dropped once the cache is over max_bytes, and files older than ttl are never served.
+pub struct DiskCache {
+    root: PathBuf,
+    max_bytes: u64,
+    ttl: Duration,
+    index: Mutex<Index>,
+    /// Numbers the files being written, so two writes of one key never share a partial file
+    writes: AtomicU64,
+}
+
+/// Every file under a directory, as paths relative to root
+fn walk(root: &Path, dir: &Path, files: &mut Vec<(String, fs::Metadata)>) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+
+        if path.is_dir() {
+            walk(root, &path, files);
+        } else if let (Ok(metadata), Ok(relative)) = (entry.metadata(), path.strip_prefix(root)) {
+            let key = relative.components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+
+            files.push((key, metadata));
+        }
+    }
+}
+
+impl DiskCache {
+    /// Open a cache directory, picking up whatever an earlier run left in it
+    pub fn new(root: PathBuf, max_bytes: u64, ttl: Duration) -> std::io::Result<DiskCache> {
+        fs::create_dir_all(&root)?;
+
+        let mut files = Vec::new();
+        walk(&root, &root, &mut files);
+
+        let mut index = Index::default();
+
+        for (key, metadata) in files {
+            if key.ends_with(PARTIAL) {
+                let _ = fs::remove_file(root.join(&key));
+                continue;
+            }
+
+            // access times are often off, so start from when the file was written
+            let written = metadata.modified().unwrap_or_else(|_| SystemTime::now());
+
+            index.total += metadata.len();
+            index.entries.insert(key, Entry { size: metadata.len(), written, last_used: written });
+        }
+
+        let cache = DiskCache {
+            root,
+            max_bytes,
+            ttl,
+            index: Mutex::new(index),
+            writes: AtomicU64::new(0),
+        };
+
+        cache.evict();
+
+        Ok(cache)
+    }
+
+    /// The cache described by the settings. None when there is no directory set
+    pub fn from_settings(settings: &DiskCacheSettings) -> Option<DiskCache> {
+        let root = settings.dir.clone()?;
+
+        DiskCache::new(root, settings.max_mb * 1024 * 1024, Duration::from_secs(settings.ttl_hours * 3600)).ok()
+    }
+
+    /// Where a key lives on disk. Keys that would leave the cache directory are refused
+    fn path(&self, key: &str) -> Option<PathBuf> {
+        let relative = Path::new(key);
+
+        if relative.components().all(|c| matches!(c, Component::Normal(_))) {
+            Some(self.root.join(relative))
+        } else {
+            None
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<Bytes> {
+        let path = self.path(key)?;
+
+        {
+            let mut index = self.index.lock().unwrap();
+            let entry = index.entries.get_mut(key)?;
+
+            let age = entry.written.elapsed().unwrap_or_default();
+            if age > self.ttl {
+                drop(index);
+                self.remove(key);
+                return None;
+            }
+
+            entry.last_used = SystemTime::now();
+        }
+
+        match fs::read(&path) {
+            Ok(data) => Some(Bytes::from(data)),
+            Err(_) => {
+                // deleted behind our back
+                self.remove(key);
+                None
+            }
+        }
+    }
+
+    pub fn put(&self, key: &str, data: &[u8]) {
+        let path = match self.path(key) {
+            Some(path) => path,
+            None => return,
+        };
+
+        // bigger than the whole cache, it would only push everything else out
+        if data.len() as u64 > self.max_bytes {
+            return;
+        }
+
+        // whichever write is renamed last wins, both files are whole
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(".{}-{}.", std::process::id(), self.writes.fetch_add(1, Ordering::Relaxed)));
+        partial.push(PARTIAL);
+        let partial = PathBuf::from(partial);
+
+        let written = path.parent().map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(&partial, data))
+            .and_then(|_| fs::rename(&partial, &path));
+
+        if written.is_err() {
+            let _ = fs::remove_file(&partial);
+            return;
+        }
+
+        {
+            let mut index = self.index.lock().unwrap();
+            let now = SystemTime::now();
+
+            if let Some(old) = index.entries.insert(key.to_string(), Entry { size: data.len() as u64, written: now, last_used: now }) {
+                index.total -= old.size;
+            }
+            index.total += data.len() as u64;
+        }
+
+        self.evict();
+    }
+
+    pub fn remove(&self, key: &str) {
+        let mut index = self.index.lock().unwrap();
+
+        if let Some(entry) = index.entries.remove(key) {
+            index.total -= entry.size;
+
+            if let Some(path) = self.path(key) {
+                let _ = fs::remove_file(path);
+            }
+        }
+    }
+
+    /// Drop expired files, then the least recently used until we are under budget
+    fn evict(&self) {
+        let mut index = self.index.lock().unwrap();
+
+        let expired: Vec<String> = index.entries.iter()
+            .filter(|(_, entry)| entry.written.elapsed().unwrap_or_default() > self.ttl)
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        let mut to_remove = expired;
+        let mut total = index.total - to_remove.iter().map(|key| index.entries[key].size).sum::<u64>();
+
+        if total > self.max_bytes {
+            let mut by_use: Vec<(&String, &Entry)> = index.entries.iter()
+                .filter(|(key, _)| !to_remove.contains(key))
+                .collect();
+            by_use.sort_by_key(|(_, entry)| entry.last_used);
+
+            let mut oldest = Vec::new();
+            for (key, entry) in by_use {
+                if total <= self.max_bytes {
+                    break;
+                }
+
+                total -= entry.size;
+                oldest.push(key.clone());
+            }
+
+            to_remove.extend(oldest);
+        }
+
+        for key in to_remove {
+            if let Some(entry) = index.entries.remove(&key) {
+                index.total -= entry.size;
+
+                if let Some(path) = self.path(key.as_str()) {
+                    let _ = fs::remove_file(path);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,429 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::cache::Storage;
+use crate::settings::EvictionSettings;
+
+/// Where the index itself is kept in the bucket. It is never indexed or evicted
+pub const INDEX_FILE: &str = "cache/index.json";
+
+/// Objects that are bookkeeping rather than cache, and are never evicted
+const KEEP_PREFIXES: [&str; 2] = ["cache/", "jobs/"];
+
+/// Times a save reads, merges and writes the index before giving up until the next save, when
+/// other instances keep writing it in between
+const SAVE_ATTEMPTS: usize = 5;
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// What we know about one object in the bucket. Times are seconds since the epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ObjectRecord {
+    pub size: u64,
+    pub created: u64,
+    pub last_access: u64,
+    pub hits: u64,
+}
+
+/// What changed in the index since it was last saved
+#[derive(Default)]
+struct Changes {
+    /// Objects written or read
+    touched: HashSet<String>,
+    /// Hits not yet added to the saved index
+    hits: HashMap<String, u64>,
+    removed: HashSet<String>,
+}
+
+impl Changes {
+    fn is_empty(&self) -> bool {
+        self.touched.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// Size, age and use of every object in the bucket, so we can decide what to evict. Kept in memory
+/// and saved to the bucket now and then, so it is a little behind after a crash. Every instance
+/// sharing the bucket keeps its own, and a save merges our changes into whatever the others saved
+#[derive(Default)]
+pub struct CacheIndex {
+    records: Mutex<HashMap<String, ObjectRecord>>,
+    changes: Mutex<Changes>,
+}
+
+impl CacheIndex {
+    fn tracked(name: &str) -> bool {
+        name != INDEX_FILE
+    }
+
+    pub fn record_write(&self, name: &str, size: u64) {
+        if !CacheIndex::tracked(name) {
+            return;
+        }
+
+        let time = now();
+        self.records.lock().unwrap().insert(name.to_string(), ObjectRecord { size, created: time, last_access: time, hits: 0 });
+
+        let mut changes = self.changes.lock().unwrap();
+        changes.removed.remove(name);
+        changes.touched.insert(name.to_string());
+    }
+
+    pub fn record_hit(&self, name: &str, size: u64) {
+        if !CacheIndex::tracked(name) {
+            return;
+        }
+
+        let time = now();
+        let mut records = self.records.lock().unwrap();
+
+        // objects from before the index existed are picked up the first time they are read
+        let record = records.entry(name.to_string())
+            .or_insert(ObjectRecord { size, created: time, last_access: time, hits: 0 });
+
+        record.last_access = time;
+        record.hits += 1;
+
+        let mut changes = self.changes.lock().unwrap();
+        changes.removed.remove(name);
+        changes.touched.insert(name.to_string());
+        *changes.hits.entry(name.to_string()).or_default() += 1;
+    }
+
+    pub fn remove(&self, name: &str) {
+        self.records.lock().unwrap().remove(name);
+
+        let mut changes = self.changes.lock().unwrap();
+        changes.touched.remove(name);
+        changes.hits.remove(name);
+        changes.removed.insert(name.to_string());
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, ObjectRecord> {
+        self.records.lock().unwrap().clone()
+    }
+
+    /// Merge a saved index with what is really in the bucket. Objects only in the bucket are added
+    /// with their upload time, and records of objects that are gone are dropped
+    pub fn rebuild(&self, saved: HashMap<String, ObjectRecord>, objects: Vec<(String, u64, u64)>) {
+        let mut records = self.records.lock().unwrap();
+        let mut changes = self.changes.lock().unwrap();
+
+        let mut listed = HashSet::new();
+
+        for (name, size, created) in objects.into_iter().filter(|(name, _, _)| CacheIndex::tracked(name)) {
+            listed.insert(name.clone());
+
+            // anything recorded since startup is newer than the saved copy
+            if records.contains_key(&name) {
+                continue;
+            }
+
+            let record = match saved.get(&name) {
+                Some(record) => ObjectRecord { size, ..record.clone() },
+                None => {
+                    changes.touched.insert(name.clone());
+                    ObjectRecord { size, created, last_access: created, hits: 0 }
+                }
+            };
+
+            records.insert(name, record);
+        }
+
+        changes.removed.extend(saved.into_keys().filter(|name| !listed.contains(name) && !records.contains_key(name)));
+    }
+
+    /// Take what changed since the last save, to be saved now
+    fn take_changes(&self) -> Changes {
+        std::mem::take(&mut *self.changes.lock().unwrap())
+    }
+
+    /// Put back changes a save could not write, under anything that changed since
+    fn restore_changes(&self, taken: Changes) {
+        let mut changes = self.changes.lock().unwrap();
+
+        for name in taken.removed {
+            if !changes.touched.contains(&name) {
+                changes.removed.insert(name);
+            }
+        }
+
+        for name in taken.touched {
+            if !changes.removed.contains(&name) {
+                changes.touched.insert(name);
+            }
+        }
+
+        for (name, hits) in taken.hits {
+            if changes.touched.contains(&name) {
+                *changes.hits.entry(name).or_default() += hits;
+            }
+        }
+    }
+
+    /// Our changes applied to the index another instance may have saved since we last read it.
+    /// Access times are the latest either side saw and hits add up
+    fn merged(&self, mut saved: HashMap<String, ObjectRecord>, changes: &Changes) -> HashMap<String, ObjectRecord> {
+        let records = self.records.lock().unwrap();
+
+        for name in &changes.removed {
+            saved.remove(name);
+        }
+
+        for name in &changes.touched {
+            if let Some(local) = records.get(name) {
+                let hits = changes.hits.get(name).copied().unwrap_or_default();
+
+                saved.entry(name.clone())
+                    .and_modify(|record| {
+                        record.size = local.size;
+                        record.created = record.created.max(local.created);
+                        record.last_access = record.last_access.max(local.last_access);
+                        record.hits += hits;
+                    })
+                    .or_insert_with(|| local.clone());
+            }
+        }
+
+        saved
+    }
+
+    /// Take the index as saved, keeping whatever changed here while it was being written
+    fn replace(&self, mut saved: HashMap<String, ObjectRecord>) {
+        let mut records = self.records.lock().unwrap();
+        let changes = self.changes.lock().unwrap();
+
+        for name in &changes.touched {
+            if let Some(local) = records.get(name) {
+                let hits = changes.hits.get(name).copied().unwrap_or_default();
+
+                saved.entry(name.clone())
+                    .and_modify(|record| {
+                        record.size = local.size;
+                        record.last_access = record.last_access.max(local.last_access);
+                        record.hits += hits;
+                    })
+                    .or_insert_with(|| local.clone());
+            }
+        }
+
+        for name in &changes.removed {
+            saved.remove(name);
+        }
+
+        *records = saved;
+    }
+}
+
+/// Which objects to drop from the bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EvictionPolicy {
+    /// Drop the least recently used objects until the bucket is under this size
+    pub max_bytes: Option<u64>,
+    /// Drop objects that have not been read for this many seconds
+    pub max_idle: Option<u64>,
+    /// Only ever drop product zips, keeping renders, overviews and metadata
+    #[serde(default)]
+    pub zips_only: bool,
+}
+
+impl EvictionPolicy {
+    /// Policy from the settings. Nothing is evicted unless one of the limits is set
+    pub fn from_settings(settings: &EvictionSettings) -> EvictionPolicy {
+        EvictionPolicy {
+            max_bytes: settings.max_gb.map(|gb| (gb * 1024.0 * 1024.0 * 1024.0) as u64),
+            max_idle: settings.max_idle_days.map(|days| (days * 86400.0) as u64),
+            zips_only: settings.zips_only,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EvictionReport {
+    pub removed: usize,
+    pub removed_bytes: u64,
+    pub remaining: usize,
+    pub remaining_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PurgeReport {
+    pub removed: usize,
+    pub removed_bytes: u64,
+}
+
+/// Totals of the bucket, split between product zips and everything else
+#[derive(Debug, Clone, Serialize)]
+pub struct CacheStats {
+    pub objects: usize,
+    pub bytes: u64,
+    pub zips: usize,
+    pub zip_bytes: u64,
+    pub hits: u64,
+}
+
+pub fn stats(index: &CacheIndex) -> CacheStats {
+    let records = index.snapshot();
+    let zips: Vec<&ObjectRecord> = records.iter().filter(|(name, _)| name.ends_with(".zip")).map(|(_, r)| r).collect();
+
+    CacheStats {
+        objects: records.len(),
+        bytes: records.values().map(|r| r.size).sum(),
+        zips: zips.len(),
+        zip_bytes: zips.iter().map(|r| r.size).sum(),
+        hits: records.values().map(|r| r.hits).sum(),
+    }
+}
+
+/// What an object is evicted along with. The bands of an overview ("{id}/overviews/{scale}/...")
+/// only work as a set, so they go together, everything else goes on its own
+fn eviction_unit(name: &str) -> &str {
+    let parts: Vec<&str> = name.splitn(4, '/').collect();
+
+    match parts.as_slice() {
+        [id, "overviews", scale, _] => &name[..id.len() + "/overviews/".len() + scale.len() + 1],
+        _ => name,
+    }
+}
+
+/// Drop idle objects, then the least recently used until the bucket is under the size limit. The
+/// index is saved first, so objects other instances have been reading are not taken for idle.
+/// Overviews are dropped whole, as recently used as their most recently used band
+pub async fn evict(storage: &Storage, policy: &EvictionPolicy) -> EvictionReport {
+    save(storage).await;
+
+    let records = storage.index().snapshot();
+    let time = now();
+
+    let mut total: u64 = records.values().map(|r| r.size).sum();
+
+    // last access, size and names of everything in a unit
+    let mut units: HashMap<&str, (u64, u64, Vec<&String>)> = HashMap::new();
+
+    for (name, record) in records.iter()
+        .filter(|(name, _)| !KEEP_PREFIXES.iter().any(|prefix| name.starts_with(prefix)))
+        .filter(|(name, _)| !policy.zips_only || name.ends_with(".zip")) {
+        let unit = units.entry(eviction_unit(name)).or_default();
+
+        unit.0 = unit.0.max(record.last_access);
+        unit.1 += record.size;
+        unit.2.push(name);
+    }
+
+    let mut candidates: Vec<(u64, u64, Vec<&String>)> = units.into_values().collect();
+    candidates.sort_by_key(|(last_access, _, _)| *last_access);
+
+    let mut to_remove = Vec::new();
+
+    for (last_access, size, names) in candidates {
+        let idle = policy.max_idle.is_some_and(|max| time.saturating_sub(last_access) > max);
+        let over = policy.max_bytes.is_some_and(|max| total > max);
+
+        if idle || over {
+            total -= size;
+            to_remove.extend(names.into_iter().map(|name| (name.clone(), records[name].size)));
+        }
+    }
+
+    let mut removed_bytes = 0;
+    for (name, size) in &to_remove {
+        storage.delete(name.as_str()).await;
+        removed_bytes += size;
+    }
+
+    EvictionReport {
+        removed: to_remove.len(),
+        removed_bytes,
+        remaining: records.len() - to_remove.len(),
+        remaining_bytes: total,
+    }
+}
+
+/// Remove everything in the bucket under prefix that matches. Works from the bucket listing rather
+/// than the index, so nothing is missed while the index is still loading. Bookkeeping is never
+/// purged
+pub async fn purge(storage: &Storage, prefix: &str, matches: impl Fn(&str) -> bool) -> PurgeReport {
+    let mut report = PurgeReport { removed: 0, removed_bytes: 0 };
+
+    for (name, size, _) in storage.list_objects(prefix).await {
+        let kept = KEEP_PREFIXES.iter().any(|prefix| name.starts_with(prefix));
+
+        if !kept && matches(name.as_str()) {
+            storage.delete(name.as_str()).await;
+
+            report.removed += 1;
+            report.removed_bytes += size;
+        }
+    }
+
+    report
+}
+
+/// Load the saved index and square it with the bucket
+pub async fn load(storage: &Storage) {
+    let saved: HashMap<String, ObjectRecord> = storage.get(INDEX_FILE).await
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+
+    let objects = storage.list_objects("").await;
+
+    storage.index().rebuild(saved, objects);
+}
+
+/// Merge what changed here into the saved index and write it back. The write only goes through if
+/// nobody saved in between, otherwise it starts over from their copy
+pub async fn save(storage: &Storage) {
+    let index = storage.index();
+    let changes = index.take_changes();
+
+    for _ in 0..SAVE_ATTEMPTS {
+        let (saved, generation) = match storage.get_versioned(INDEX_FILE).await {
+            Some((data, generation)) => (serde_json::from_slice(&data).unwrap_or_default(), generation),
+            // never saved, or the bucket could not be read. Either way keep what we have
+            None if changes.is_empty() => return,
+            None => (HashMap::new(), 0),
+        };
+
+        // nothing of ours to write, but we still pick up what the others saw
+        if changes.is_empty() {
+            index.replace(saved);
+            return;
+        }
+
+        let merged = index.merged(saved, &changes);
+
+        if storage.put_if_generation(INDEX_FILE, serde_json::to_vec(&merged).unwrap(), generation).await {
+            index.replace(merged);
+            return;
+        }
+    }
+
+    warn!("Could not save the cache index, trying again next time");
+    index.restore_changes(changes);
+}
+
+/// Keep the index saved and run the eviction policy every interval, forever. Meant to be spawned
+pub async fn maintain(storage: Storage, policy: EvictionPolicy, interval: Duration) {
+    load(&storage).await;
+
+    let save_every = Duration::from_secs(600);
+    let mut since_eviction = Duration::ZERO;
+
+    loop {
+        tokio::time::sleep(save_every).await;
+        since_eviction += save_every;
+
+        if since_eviction >= interval && (policy.max_bytes.is_some() || policy.max_idle.is_some()) {
+            since_eviction = Duration::ZERO;
+
+            let report = evict(&storage, &policy).await;
+            info!("Evicted {} objects ({} bytes) from the bucket", report.removed, report.removed_bytes);
+        }
+
+        save(&storage).await;
+    }
+}
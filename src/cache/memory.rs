@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::sat_data::SatData;
+use crate::settings::MemoryCacheSettings;
+
+struct Entry {
+    data: Arc<SatData>,
+    size: usize,
+    last_used: u64,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    total: usize,
+    /// Counts up on every use, so the smallest last_used is the least recently used
+    clock: u64,
+}
+
+/// Recently decoded products kept in memory, so a second filter on the same product skips the
+/// zip. Once the decoded bands add up to more than the budget the least recently used product is
+/// dropped
+pub struct SatDataCache {
+    budget: usize,
+    state: Mutex<State>,
+}
+
+impl SatDataCache {
+    pub fn new(budget: usize) -> SatDataCache {
+        SatDataCache {
+            budget,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Budget from the settings, 0 turns the cache off
+    pub fn from_settings(settings: &MemoryCacheSettings) -> SatDataCache {
+        SatDataCache::new(settings.max_mb * 1024 * 1024)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Arc<SatData>> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+
+        let clock = state.clock;
+        let entry = state.entries.get_mut(key)?;
+        entry.last_used = clock;
+
+        Some(entry.data.clone())
+    }
+
+    pub fn insert(&self, key: &str, data: Arc<SatData>) {
+        let size = data.size_bytes();
+
+        // it would push out everything else and still not fit
+        if size > self.budget {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+
+        let entry = Entry { data, size, last_used: state.clock };
+        if let Some(old) = state.entries.insert(key.to_string(), entry) {
+            state.total -= old.size;
+        }
+        state.total += size;
+
+        while state.total > self.budget {
+            let oldest = state.entries.iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            match oldest.and_then(|key| state.entries.remove(&key)) {
+                Some(removed) => state.total -= removed.size,
+                None => break,
+            }
+        }
+    }
+}
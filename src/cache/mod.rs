@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use bytes::Bytes;
+use google_cloud_storage::http::objects::delete::DeleteObjectRequest;
+use google_cloud_storage::http::objects::download::Range;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use google_cloud_storage::http::objects::list::ListObjectsRequest;
+use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
+use google_cloud_storage::http::Error;
+use tokio::task::spawn_blocking;
+
+use crate::cache::disk::DiskCache;
+use crate::cache::index::CacheIndex;
+
+pub mod disk;
+pub mod index;
+pub mod memory;
+
+/// Objects other instances change, like jobs, which a local copy would only let go stale
+fn shared(name: &str) -> bool {
+    name.starts_with("jobs/") || name.starts_with("cache/")
+}
+
+/// Where everything we keep is stored: the google bucket, with an optional local disk cache in
+/// front of it. Reads try the disk first and copy bucket hits to it, writes go to both. Every read
+/// and write is noted in the index, which eviction works from.
+#[derive(Clone)]
+pub struct Storage {
+    client: google_cloud_storage::client::Client,
+    bucket: String,
+    disk: Option<Arc<DiskCache>>,
+    index: Arc<CacheIndex>,
+}
+
+impl Storage {
+    pub fn new(client: google_cloud_storage::client::Client, bucket: &str, disk: Option<DiskCache>) -> Storage {
+        Storage {
+            client,
+            bucket: bucket.to_string(),
+            disk: disk.map(Arc::new),
+            index: Arc::new(CacheIndex::default()),
+        }
+    }
+
+    pub fn index(&self) -> &CacheIndex {
+        &self.index
+    }
+
+    /// The disk cache, unless the object is one other instances change
+    fn disk_for(&self, name: &str) -> Option<Arc<DiskCache>> {
+        self.disk.clone().filter(|_| !shared(name))
+    }
+
+    /// Read from the disk cache off the async threads
+    async fn disk_get(&self, name: &str) -> Option<Bytes> {
+        let disk = self.disk_for(name)?;
+        let name = name.to_string();
+
+        spawn_blocking(move || disk.get(name.as_str())).await.unwrap()
+    }
+
+    /// Write to the disk cache off the async threads
+    async fn disk_put(&self, name: &str, data: Bytes) {
+        if let Some(disk) = self.disk_for(name) {
+            let name = name.to_string();
+
+            spawn_blocking(move || disk.put(name.as_str(), &data)).await.unwrap();
+        }
+    }
+
+    pub async fn get(&self, name: &str) -> Option<Bytes> {
+        if let Some(data) = self.disk_get(name).await {
+            self.index.record_hit(name, data.len() as u64);
+            return Some(data);
+        }
+
+        let data_result = self.client.download_object(&GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: name.to_string(),
+            ..Default::default()
+        }, &Range::default()).await;
+
+        if let Ok(data) = data_result {
+            let data = Bytes::from(data);
+            self.disk_put(name, data.clone()).await;
+
+            self.index.record_hit(name, data.len() as u64);
+            Some(data)
+        } else {
+            None
+        }
+    }
+
+    pub async fn put(&self, name: &str, data: impl Into<Bytes>) {
+        let data = data.into();
+
+        self.disk_put(name, data.clone()).await;
+
+        let size = data.len() as u64;
+        let upload_type = UploadType::Simple(Media::new(name.to_string()));
+
+        let result = self.client.upload_object(&UploadObjectRequest {
+            bucket: self.bucket.clone(),
+            ..Default::default()
+        }, data, &upload_type).await;
+
+        // the bucket is a cache, failing to fill it must not fail the request
+        if let Err(e) = result {
+            warn!("Could not write {name} to the bucket: {e}");
+            return;
+        }
+
+        self.index.record_write(name, size);
+    }
+
+    /// An object straight from the bucket along with its generation, for writing it back with
+    /// put_if_generation. None when it is not there
+    pub async fn get_versioned(&self, name: &str) -> Option<(Bytes, i64)> {
+        let object = self.client.get_object(&GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: name.to_string(),
+            ..Default::default()
+        }).await.ok()?;
+
+        // the generation we were told about, even if it has been replaced since
+        let data = self.client.download_object(&GetObjectRequest {
+            bucket: self.bucket.clone(),
+            object: name.to_string(),
+            generation: Some(object.generation),
+            ..Default::default()
+        }, &Range::default()).await.ok()?;
+
+        self.index.record_hit(name, data.len() as u64);
+        Some((Bytes::from(data), object.generation))
+    }
+
+    /// Write an object only if the bucket still has the given generation of it, 0 meaning it must
+    /// not exist yet. Returns false when someone else wrote it first, or the write failed
+    pub async fn put_if_generation(&self, name: &str, data: impl Into<Bytes>, generation: i64) -> bool {
+        let data = data.into();
+        let size = data.len() as u64;
+        let upload_type = UploadType::Simple(Media::new(name.to_string()));
+
+        let result = self.client.upload_object(&UploadObjectRequest {
+            bucket: self.bucket.clone(),
+            if_generation_match: Some(generation),
+            ..Default::default()
+        }, data, &upload_type).await;
+
+        match result {
+            Ok(_) => {
+                self.index.record_write(name, size);
+                true
+            }
+            Err(Error::Response(e)) if e.code == 412 => false,
+            Err(e) => {
+                warn!("Could not write {name} to the bucket: {e}");
+                false
+            }
+        }
+    }
+
+    /// Remove an object from the bucket and the disk cache
+    pub async fn delete(&self, name: &str) {
+        if let Some(disk) = self.disk_for(name) {
+            disk.remove(name);
+        }
+
+        let result = self.client.delete_object(&DeleteObjectRequest {
+            bucket: self.bucket.clone(),
+            object: name.to_string(),
+            ..Default::default()
+        }).await;
+
+        if let Err(e) = result {
+            warn!("Could not delete {name} from the bucket: {e}");
+        }
+
+        self.index.remove(name);
+    }
+
+    /// Make sure the bucket is there and we may read it
+    pub async fn check(&self) -> anyhow::Result<()> {
+        self.client.list_objects(&ListObjectsRequest {
+            bucket: self.bucket.clone(),
+            max_results: Some(1),
+            ..Default::default()
+        }).await.with_context(|| format!("Could not reach bucket {}", self.bucket))?;
+
+        Ok(())
+    }
+
+    /// Names of everything in the bucket starting with prefix
+    pub async fn list(&self, prefix: &str) -> Vec<String> {
+        self.list_objects(prefix).await.into_iter().map(|(name, _, _)| name).collect()
+    }
+
+    /// Name, size and upload time (seconds since the epoch) of everything in the bucket starting
+    /// with prefix
+    pub async fn list_objects(&self, prefix: &str) -> Vec<(String, u64, u64)> {
+        let mut objects = Vec::new();
+        let mut page_token = None;
+
+        loop {
+            let response = self.client.list_objects(&ListObjectsRequest {
+                bucket: self.bucket.clone(),
+                prefix: Some(prefix.to_string()),
+                page_token,
+                ..Default::default()
+            }).await;
+
+            let response = match response {
+                Ok(response) => response,
+                Err(_) => break,
+            };
+
+            objects.extend(response.items.unwrap_or_default().into_iter().map(|object| {
+                let created = object.time_created.map(|t| t.unix_timestamp().max(0) as u64).unwrap_or_default();
+                (object.name, object.size.max(0) as u64, created)
+            }));
+
+            page_token = response.next_page_token;
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        objects
+    }
+}
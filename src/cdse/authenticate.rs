@@ -1,44 +1,43 @@
-use std::io::Read;
-
 use reqwest::header;
 use serde_json::json;
 
+use crate::cdse::http::{CdseError, CdseHttp, Endpoint};
+use crate::settings::CdseSettings;
+
 /// This will pass username and password to cdse and return a api access token
-pub fn authenticate(client: &reqwest::blocking::Client, username: &str, password: &str) -> String {
+pub async fn authenticate(http: &CdseHttp, settings: &CdseSettings, username: &str, password: &str) -> Result<String, CdseError> {
     let request_data = json!({
-        "client_id": "cdse-public",
+        "client_id": settings.client_id,
         "username": username,
         "password": password,
         "grant_type": "password",
     });
 
-    let mut buffer = String::new();
-
-    let mut res = client
-        .post("https://identity.dataspace.copernicus.eu/auth/realms/CDSE/protocol/openid-connect/token")
-        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .form(&request_data)
-        .send()
-        .unwrap();
+    let response = http.json(Endpoint::Token, || {
+        http.client()
+            .post(settings.token_url.as_str())
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .form(&request_data)
+    }).await?;
 
-    res.read_to_string(&mut buffer).unwrap();
-
-    let response: serde_json::Value = serde_json::from_str(buffer.as_str()).unwrap();
-
-    response["access_token"].as_str().unwrap().to_string()
+    response["access_token"].as_str()
+        .map(str::to_string)
+        .ok_or_else(|| CdseError::BadResponse { endpoint: Endpoint::Token, reason: "no access token".to_string() })
 }
 
-pub fn refresh(client: &reqwest::blocking::Client, token: &str){
+/// Not used yet, a token is fetched for every download for now
+#[allow(dead_code)]
+pub async fn refresh(http: &CdseHttp, settings: &CdseSettings, token: &str) -> Result<(), CdseError> {
     let request_data = json!({
-        "client_id": "cdse-public",
+        "client_id": settings.client_id,
         "refresh_token": token,
         "grant_type": "refresh_token",
     });
 
-    client
-        .post("https://identity.dataspace.copernicus.eu/auth/realms/CDSE/protocol/openid-connect/token")
-        .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
-        .form(&request_data)
-        .send()
-        .unwrap();
-}
\ No newline at end of file
+    http.send(Endpoint::Token, || {
+        http.client()
+            .post(settings.token_url.as_str())
+            .header(header::CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .form(&request_data)
+    }).await
+}
@@ -2,113 +2,49 @@ use std::io::Cursor;
 use anyhow::Error;
 
 use bytes::Bytes;
-use google_cloud_storage::client::{Client, ClientConfig};
-use google_cloud_storage::http::objects::download::Range;
-use google_cloud_storage::http::objects::get::GetObjectRequest;
-use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
-use reqwest::redirect::Policy;
 use zip::ZipArchive;
 
+use crate::cache::Storage;
+use crate::cdse::http::{CdseError, CdseHttp, Endpoint};
+
 fn unzip_in_memory(data: Bytes) -> anyhow::Result<ZipArchive<Cursor<Bytes>>> {
-    let data_clone = data.clone();
+    let size = data.len();
     let mut reader = Cursor::new(data);
 
     reader.set_position(0);
 
-    let check = ZipArchive::new(reader);
-
-    if let Ok(good) = check{
-        Ok(good)
-    }else {
-
-        dbg!(data_clone);
-
-        Err(Error::msg("Failed to load zip"))
-    }
+    ZipArchive::new(reader).map_err(|e| Error::msg(format!("Failed to load zip of {size} bytes: {e}")))
 }
 
-/// This will check if a given file is already stored in the google bucket
-async fn google_bucket_check(client: &Client, filename: &str) -> Option<ZipArchive<Cursor<Bytes>>> {
-    // check for file
-    let data = client.download_object(&GetObjectRequest {
-        bucket: "satellite-storage".to_string(),
-        object: filename.to_string(),
-        ..Default::default()
-    }, &Range::default()).await;
+/// The zip of a product if we downloaded it before
+pub async fn cached(storage: &Storage, id: &str) -> Option<ZipArchive<Cursor<Bytes>>> {
+    let data = storage.get(format!("{id}.zip").as_str()).await?;
 
-    if let Ok(file) = data {
-        Some(unzip_in_memory(Bytes::from(file)).unwrap())
-    } else {
-        None
+    match unzip_in_memory(data) {
+        Ok(zip) => Some(zip),
+        Err(e) => {
+            warn!("Cached zip of {id} is broken, downloading it again: {e}");
+            None
+        }
     }
 }
 
-/// This will upload a zip file to google bucket
-async fn google_bucket_upload_zip(filename: &str, file: &Bytes) {
-    // authenticate
-    let config = ClientConfig::default().with_auth().await.unwrap();
-    let client = Client::new(config);
-
-    let upload_type = UploadType::Simple(Media::new(filename.to_string()));
-    client.upload_object(&UploadObjectRequest {
-        bucket: "satellite-storage".to_string(),
-        ..Default::default()
-    }, file.to_owned(), &upload_type).await.unwrap();
-}
-
-/// This will download data and unzip it from ESA. This will be returned as a zipped object
-pub async fn download(google_client: &Client, id: &str, token: &str) -> ZipArchive<Cursor<Bytes>> {
+/// This will download data and unzip it from ESA. This will be returned as a zipped object. The
+/// product has to be online
+pub async fn download(storage: &Storage, http: &CdseHttp, catalogue_url: &str, id: &str, token: &str) -> Result<ZipArchive<Cursor<Bytes>>, CdseError> {
     let filename = format!("{id}.zip");
 
-    dbg!("Checking database...");
-
-    if let Some(out) = google_bucket_check(google_client, filename.as_str()).await {
-        return out;
-    }
-
-    dbg!("Downloading from ESA...");
+    info!("Downloading {id} from CDSE");
 
-    // create client data to
-    let client = reqwest::blocking::Client::builder().redirect(Policy::none()).build().unwrap();
-    let mut url = format!("https://catalogue.dataspace.copernicus.eu/odata/v1/Products({})/$value", id);
+    let url = format!("{catalogue_url}/Products({id})/$value");
+    let data = http.get_with_token(Endpoint::Download, url.as_str(), token).await?;
 
-    // get initial request
-    let mut resp = client
-        .get(url.as_str())
-        .bearer_auth(token)
-        .send()
-        .unwrap();
-
-    // follow redirects
-    let redirect_codes = [301, 302, 303, 307];
-    while redirect_codes.contains(&resp.status().as_u16()) {
-        let wtf_header = resp.headers();
-
-        url = wtf_header["location"].to_str().unwrap().to_string();
-
-        resp = client
-            .get(url.as_str())
-            .bearer_auth(token)
-            .send()
-            .unwrap();
-    }
-
-    let client_with_redirect = reqwest::blocking::Client::builder().timeout(None).build().unwrap();
-
-    resp = client_with_redirect
-        .get(url.as_str())
-        .bearer_auth(token)
-        .send()
-        .unwrap();
-
-    let data = resp.bytes().unwrap();
-
-    dbg!("Uploading ZIP to database...");
+    // a broken zip is not worth keeping
+    let zip = unzip_in_memory(data.clone())
+        .map_err(|e| CdseError::BadResponse { endpoint: Endpoint::Download, reason: e.to_string() })?;
 
     // upload copy
-    google_bucket_upload_zip(filename.as_str(), &data).await;
+    storage.put(filename.as_str(), data).await;
 
-    dbg!("Returning unzipped files...");
-
-    unzip_in_memory(data).unwrap()
-}
\ No newline at end of file
+    Ok(zip)
+}
@@ -0,0 +1,278 @@
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use bytes::Bytes;
+use reqwest::header::RETRY_AFTER;
+use reqwest::redirect::Policy;
+use reqwest::{Client, RequestBuilder, Response};
+use tokio::sync::Semaphore;
+
+use crate::settings::CdseSettings;
+
+/// Redirects followed for one download before the chain is taken for a loop
+const MAX_REDIRECTS: usize = 10;
+
+/// Statuses that send a download somewhere else
+const REDIRECT_CODES: [u16; 5] = [301, 302, 303, 307, 308];
+
+/// The CDSE services we talk to. Each has its own quota, so each has its own limit on requests in
+/// flight
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Endpoint {
+    /// The identity server handing out access tokens
+    Token,
+    /// OData catalogue searches and node listings
+    Catalogue,
+    /// Product zips and files inside them, through the zipper
+    Download,
+}
+
+/// Why we could not get something from CDSE, once retrying did not help
+#[derive(Debug, Clone)]
+pub enum CdseError {
+    /// The product is in the long term archive. It has been ordered, ask again in retry_after
+    /// seconds
+    Pending { id: String, retry_after: u64 },
+    /// Still told to slow down (429) after every retry. retry_after is what the server last asked
+    /// for, in seconds
+    RateLimited { endpoint: Endpoint, retry_after: Option<u64> },
+    /// The service kept failing (5xx) or could not be reached
+    Unavailable { endpoint: Endpoint, reason: String },
+    /// The request was refused (4xx), retrying it will not help
+    Rejected { endpoint: Endpoint, status: u16 },
+    /// The service answered, but not with what we expected
+    BadResponse { endpoint: Endpoint, reason: String },
+    /// The product downloaded but could not be read, like a Sentinel 1 product sent horizontally
+    BadProduct { id: String, reason: String },
+    /// The search found no products
+    NotFound,
+    /// What we were asked to look for makes no sense, so CDSE was not asked at all
+    BadRequest { reason: String },
+}
+
+impl Display for CdseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CdseError::Pending { id, .. } => write!(f, "{id} is being restored from the long term archive"),
+            CdseError::RateLimited { endpoint, retry_after: Some(secs) } => write!(f, "CDSE {endpoint:?} is rate limited, retry after {secs}s"),
+            CdseError::RateLimited { endpoint, retry_after: None } => write!(f, "CDSE {endpoint:?} is rate limited"),
+            CdseError::Unavailable { endpoint, reason } => write!(f, "CDSE {endpoint:?} is unavailable: {reason}"),
+            CdseError::Rejected { endpoint, status } => write!(f, "CDSE {endpoint:?} refused the request with {status}"),
+            CdseError::BadResponse { endpoint, reason } => write!(f, "CDSE {endpoint:?} gave a bad response: {reason}"),
+            CdseError::BadProduct { id, reason } => write!(f, "{id} could not be read: {reason}"),
+            CdseError::NotFound => write!(f, "No product matches the search"),
+            CdseError::BadRequest { reason } => write!(f, "Bad search: {reason}"),
+        }
+    }
+}
+
+impl std::error::Error for CdseError {}
+
+/// How one attempt went wrong
+enum Failure {
+    /// Worth trying again, after at least retry_after if the server asked for it
+    Transient { status: Option<u16>, retry_after: Option<Duration>, reason: String },
+    Permanent(CdseError),
+}
+
+impl Failure {
+    fn network(e: reqwest::Error) -> Failure {
+        Failure::Transient { status: None, retry_after: None, reason: e.to_string() }
+    }
+}
+
+/// Retry-After as a number of seconds. The HTTP date form is left to the backoff
+fn retry_after(response: &Response) -> Option<Duration> {
+    let secs: u64 = response.headers().get(RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+
+    Some(Duration::from_secs(secs))
+}
+
+/// Sort a response into success (including redirects), something to retry, or a refusal
+fn check(endpoint: Endpoint, response: Response) -> Result<Response, Failure> {
+    let status = response.status();
+
+    if status.as_u16() == 429 || status.is_server_error() {
+        Err(Failure::Transient {
+            status: Some(status.as_u16()),
+            retry_after: retry_after(&response),
+            reason: status.to_string(),
+        })
+    } else if status.is_client_error() {
+        Err(Failure::Permanent(CdseError::Rejected { endpoint, status: status.as_u16() }))
+    } else {
+        Ok(response)
+    }
+}
+
+/// A random fraction between 0 and 1. Only used to spread retries out, so it does not need to be
+/// good
+fn jitter() -> f64 {
+    (RandomState::new().build_hasher().finish() % 1000) as f64 / 1000.0
+}
+
+/// Clients and limits shared by every request to CDSE. Transient failures (429, 5xx and network
+/// errors) are retried with exponential backoff and jitter, waiting at least as long as Retry-After
+/// asks, and every endpoint has a cap on requests in flight so we stay inside the CDSE quotas
+pub struct CdseHttp {
+    /// Catalogue and login requests. Connections are pooled, so keep the one client
+    client: Client,
+    /// Product downloads. Redirects are followed by hand so the token survives a change of host.
+    /// Zips are large, so rather than a timeout on the whole download every wait for an answer or
+    /// the next chunk of the body is limited by read_timeout
+    download_client: Client,
+    read_timeout: Duration,
+    max_retries: u32,
+    retry_base: Duration,
+    retry_max: Duration,
+    token_requests: Semaphore,
+    catalogue_requests: Semaphore,
+    downloads: Semaphore,
+}
+
+impl CdseHttp {
+    pub fn from_settings(settings: &CdseSettings) -> anyhow::Result<CdseHttp> {
+        let connect_timeout = Duration::from_secs(settings.connect_timeout_secs);
+
+        Ok(CdseHttp {
+            client: Client::builder()
+                .connect_timeout(connect_timeout)
+                .timeout(Duration::from_secs(settings.timeout_secs))
+                .build()?,
+            download_client: Client::builder()
+                .redirect(Policy::none())
+                .connect_timeout(connect_timeout)
+                .build()?,
+            read_timeout: Duration::from_secs(settings.read_timeout_secs),
+            max_retries: settings.max_retries,
+            retry_base: Duration::from_millis(settings.retry_base_ms),
+            retry_max: Duration::from_secs(settings.retry_max_secs),
+            token_requests: Semaphore::new(settings.token_requests),
+            catalogue_requests: Semaphore::new(settings.catalogue_requests),
+            downloads: Semaphore::new(settings.downloads),
+        })
+    }
+
+    pub fn client(&self) -> &Client {
+        &self.client
+    }
+
+    fn limit(&self, endpoint: Endpoint) -> &Semaphore {
+        match endpoint {
+            Endpoint::Token => &self.token_requests,
+            Endpoint::Catalogue => &self.catalogue_requests,
+            Endpoint::Download => &self.downloads,
+        }
+    }
+
+    /// Half the doubled delay plus a random part of the other half, so clients that failed
+    /// together do not all come back together
+    fn backoff(&self, retry: u32) -> Duration {
+        let delay = self.retry_base.saturating_mul(2u32.saturating_pow(retry)).min(self.retry_max);
+
+        delay / 2 + delay.mul_f64(jitter() / 2.0)
+    }
+
+    /// Run an attempt until it works, fails for good, or runs out of retries. The endpoint's limit
+    /// is held for each attempt, not while waiting to retry
+    async fn retry<T, F, Fut>(&self, endpoint: Endpoint, mut attempt: F) -> Result<T, CdseError>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T, Failure>>,
+    {
+        let mut retries = 0;
+
+        loop {
+            let outcome = {
+                let _permit = self.limit(endpoint).acquire().await.unwrap();
+                attempt().await
+            };
+
+            let (status, retry_after, reason) = match outcome {
+                Ok(value) => return Ok(value),
+                Err(Failure::Permanent(e)) => return Err(e),
+                Err(Failure::Transient { status, retry_after, reason }) => (status, retry_after, reason),
+            };
+
+            if retries >= self.max_retries {
+                return Err(match status {
+                    Some(429) => CdseError::RateLimited { endpoint, retry_after: retry_after.map(|d| d.as_secs()) },
+                    _ => CdseError::Unavailable { endpoint, reason },
+                });
+            }
+
+            let wait = self.backoff(retries).max(retry_after.unwrap_or_default()).min(self.retry_max);
+            warn!("CDSE {endpoint:?}: {reason}, retrying in {wait:?}");
+
+            tokio::time::sleep(wait).await;
+            retries += 1;
+        }
+    }
+
+    /// Send a request and read the JSON it returns. request is called again for every retry
+    pub async fn json(&self, endpoint: Endpoint, request: impl Fn() -> RequestBuilder) -> Result<serde_json::Value, CdseError> {
+        let request = &request;
+
+        self.retry(endpoint, move || async move {
+            let response = check(endpoint, request().send().await.map_err(Failure::network)?)?;
+
+            response.json::<serde_json::Value>().await.map_err(|e| if e.is_decode() {
+                Failure::Permanent(CdseError::BadResponse { endpoint, reason: e.to_string() })
+            } else {
+                Failure::network(e)
+            })
+        }).await
+    }
+
+    /// Send a request where only the answer's status matters
+    pub async fn send(&self, endpoint: Endpoint, request: impl Fn() -> RequestBuilder) -> Result<(), CdseError> {
+        let request = &request;
+
+        self.retry(endpoint, move || async move {
+            check(endpoint, request().send().await.map_err(Failure::network)?).map(|_| ())
+        }).await
+    }
+
+    /// Run one read of a download, giving up on it when nothing comes for read_timeout
+    async fn read<T>(&self, url: &str, read: impl Future<Output = reqwest::Result<T>>) -> Result<T, Failure> {
+        match tokio::time::timeout(self.read_timeout, read).await {
+            Ok(result) => result.map_err(Failure::network),
+            Err(_) => Err(Failure::Transient { status: None, retry_after: None, reason: format!("nothing from {url} for {:?}", self.read_timeout) }),
+        }
+    }
+
+    /// Get a file with a token, following up to MAX_REDIRECTS redirects by hand so the token is kept
+    /// when they change host. The whole chain is retried, and the endpoint's limit is held until the
+    /// body is read
+    pub async fn get_with_token(&self, endpoint: Endpoint, url: &str, token: &str) -> Result<Bytes, CdseError> {
+        self.retry(endpoint, move || async move {
+            let mut url = url.to_string();
+            let mut response = check(endpoint, self.read(url.as_str(), self.download_client.get(url.as_str()).bearer_auth(token).send()).await?)?;
+            let mut hops = 0;
+
+            while REDIRECT_CODES.contains(&response.status().as_u16()) {
+                if hops == MAX_REDIRECTS {
+                    return Err(Failure::Permanent(CdseError::BadResponse { endpoint, reason: format!("more than {MAX_REDIRECTS} redirects, last to {url}") }));
+                }
+                hops += 1;
+
+                url = response.headers().get("location")
+                    .and_then(|x| x.to_str().ok())
+                    .ok_or_else(|| Failure::Permanent(CdseError::BadResponse { endpoint, reason: format!("{url} redirected nowhere") }))?
+                    .to_string();
+
+                response = check(endpoint, self.read(url.as_str(), self.download_client.get(url.as_str()).bearer_auth(token).send()).await?)?;
+            }
+
+            let mut body = Vec::with_capacity(response.content_length().unwrap_or_default() as usize);
+            while let Some(chunk) = self.read(url.as_str(), response.chunk()).await? {
+                body.extend_from_slice(&chunk);
+            }
+
+            Ok(Bytes::from(body))
+        }).await
+    }
+}
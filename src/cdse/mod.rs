@@ -1,134 +1,460 @@
-use std::thread;
+use std::io::Cursor;
+use std::sync::Arc;
 
+use anyhow::Context;
 use bytes::Bytes;
 use google_cloud_storage::client::ClientConfig;
-use google_cloud_storage::http::objects::download::Range;
-use google_cloud_storage::http::objects::get::GetObjectRequest;
-use google_cloud_storage::http::objects::upload::{Media, UploadObjectRequest, UploadType};
 use opencv::core::Vector;
-use reqwest::blocking::Client;
-use tokio::runtime::Runtime;
-use crate::cdse::authenticate::refresh;
+use opencv::imgcodecs::{IMREAD_COLOR, IMREAD_REDUCED_COLOR_2, IMREAD_REDUCED_COLOR_4, IMREAD_REDUCED_COLOR_8};
+use tokio::task::spawn_blocking;
+use zip::ZipArchive;
 
-use crate::filters::{false_color, ndwi, swir, true_color};
-use crate::sat_data::SatData;
+use crate::cache::disk::DiskCache;
+use crate::cache::index::{self, PurgeReport};
+use crate::cache::memory::SatDataCache;
+use crate::cache::Storage;
+use crate::cdse::http::{CdseError, CdseHttp};
+use crate::cdse::order::Orders;
+use crate::cdse::precache::PrecachePool;
+use crate::cdse::preview::PreviewSource;
+use crate::cdse::search::{CDSESearch, Found, MAX_PRODUCTS, PAGE_SIZE};
+use crate::cdse::search_result::SearchResult;
+use crate::cdse::single_flight::SingleFlight;
+use crate::encoding::Format;
+use crate::filters::{apply_filter, filter_version, fit, raw_composite};
+use crate::filters::index::Index;
+use crate::filters::sar::{self, SarFilter};
+use crate::metadata::TileMetadata;
+use crate::sat_data::{overview_for_size, SatData, OVERVIEW_MANIFEST};
+use crate::settings::{CdseSettings, Settings};
 
 pub mod search_result;
 mod download;
 pub mod search;
 mod authenticate;
+pub mod http;
+mod order;
+pub mod preview;
+mod single_flight;
+pub mod precache;
 
-async fn upload_image_to_bucket(client: &google_cloud_storage::client::Client, id: &str, filter: &str, sat_data: &SatData) {
-
-    // default to true color for check
-    let image = if filter == "False Color" {
-        false_color(sat_data)
-    } else if filter == "NDWI" {
-        ndwi(sat_data)
-    } else if filter == "SWIR" {
-        swir(sat_data)
-    } else {
-        true_color(sat_data)
-    };
-
-    // prepare image
-    let dir = id.to_owned() + "/" + filter + ".jpg";
-    let mut image_bytes = Vector::new();
-    opencv::imgcodecs::imencode(".jpg", &image, &mut image_bytes, &Default::default()).unwrap();
-
-    let upload_type = UploadType::Simple(Media::new(dir));
-
-    client.upload_object(&UploadObjectRequest {
-        bucket: "satellite-storage".to_string(),
-        ..Default::default()
-    }, Bytes::from(image_bytes.to_vec()), &upload_type).await.unwrap();
+/// Where a filter render is kept in the bucket. The filter's version is part of the name so renders
+/// from an older filter are never served, and every scale and format has its own file. Enhancements
+/// and max sizes are applied to the cached render on the way out, so they are not part of it
+fn image_cache_name(id: &str, filter: &str, scale: u32, format: Format) -> String {
+    format!("{id}/renders/{filter}/v{}/{scale}x.{}", filter_version(filter), format.cache_suffix())
+}
+
+/// Whether a bucket object is a render or a tile, of the given filter if there is one. Renders
+/// from before versioning were kept straight under the product as "{filter}.{suffix}" or
+/// "{filter}_{scale}x.{suffix}"
+fn is_render(name: &str, filter: Option<&str>) -> bool {
+    let parts: Vec<&str> = name.split('/').collect();
+
+    match parts.as_slice() {
+        [_, "renders" | "tiles", f, ..] => filter.is_none() || filter == Some(*f),
+        [_, file] if *file != "metadata.json" => match filter {
+            Some(filter) => file.starts_with(format!("{filter}.").as_str()) || file.starts_with(format!("{filter}_").as_str()),
+            None => true,
+        },
+        _ => false,
+    }
+}
+
+/// Run a filter and encode it. Raw arrays of an index name get the index values themselves rather
+/// than the colored render
+fn render(filter: &str, sat_data: &SatData, format: Format) -> Vec<u8> {
+    // radar filters give dB, or the dB difference for VV-VH
+    if let Some(bands) = sat_data.sar().filter(|_| format == Format::Npy) {
+        if let Some(values) = SarFilter::from_name(filter).and_then(|f| sar::values(f, bands, sat_data.scale())) {
+            return format.encode(&values);
+        }
+
+        return format.encode(&apply_filter(filter, sat_data));
+    }
+
+    // tiffs of band composites keep the digital numbers
+    if format == Format::Tiff {
+        if let Some(composite) = raw_composite(filter, sat_data) {
+            return format.encode(&composite);
+        }
+    }
+
+    match Index::from_name(filter).filter(|_| format == Format::Npy) {
+        Some(index) => format.encode(&index.compute(sat_data)),
+        None => format.encode(&apply_filter(filter, sat_data)),
+    }
+}
+
+/// Keep the tile metadata next to the renders so georeferencing does not need the zip again
+async fn upload_metadata_to_bucket(storage: &Storage, id: &str, sat_data: &SatData) {
+    if let Some(metadata) = sat_data.metadata() {
+        let dir = id.to_owned() + "/metadata.json";
+
+        storage.put(dir.as_str(), serde_json::to_vec(metadata).unwrap()).await;
+    }
+}
+
+/// Keep the bands of an overview as png so later requests at that scale skip the zip entirely. The
+/// manifest goes last, so an overview without one was never finished
+async fn upload_overview_to_bucket(storage: &Storage, id: &str, overview: Arc<SatData>) {
+    let owned = overview.clone();
+    let bands = spawn_blocking(move || owned.encode_bands()).await.unwrap();
+
+    let dir = format!("{id}/overviews/{}/", overview.scale());
+    let names: Vec<&String> = bands.iter().map(|(name, _)| name).collect();
+    let manifest = serde_json::to_vec(&names).unwrap();
+
+    for (name, data) in bands {
+        storage.put((dir.clone() + name.as_str()).as_str(), data).await;
+    }
+
+    storage.put((dir + OVERVIEW_MANIFEST).as_str(), manifest).await;
+
+    upload_metadata_to_bucket(storage, id, &overview).await;
 }
 
 pub struct CDSE {
-    cdse_client: Client,
-    google_client: google_cloud_storage::client::Client,
+    http: CdseHttp,
+    storage: Storage,
+    settings: CdseSettings,
     username: String,
     password: String,
+    /// Product zips being downloaded, by id
+    downloads: SingleFlight<Result<ZipArchive<Cursor<Bytes>>, CdseError>>,
+    /// Products ordered from the long term archive
+    orders: Orders,
+    /// Images being rendered, by where they will be cached
+    renders: SingleFlight<Result<Vec<u8>, CdseError>>,
+    /// Recently decoded products, by id and scale
+    decoded: SatDataCache,
+    precache: PrecachePool,
 }
 
 impl CDSE {
     /// Create new CDSE instance
-    pub async fn new(username: &str, password: &str) -> CDSE {
+    pub async fn new(settings: &Settings, username: &str, password: &str) -> anyhow::Result<CDSE> {
         // create clients
-        let cdse_client = Client::new();
+        let http = CdseHttp::from_settings(&settings.cdse).context("Could not create the CDSE client")?;
 
         // authenticate
-        let config = ClientConfig::default().with_auth().await.unwrap();
+        let config = ClientConfig::default().with_auth().await.context("Could not authenticate with google cloud")?;
         let google_client = google_cloud_storage::client::Client::new(config);
 
-        CDSE {
-            cdse_client,
-            google_client,
+        let storage = Storage::new(google_client, settings.bucket.name.as_str(), DiskCache::from_settings(&settings.disk_cache));
+        let decoded = SatDataCache::from_settings(&settings.memory_cache);
+
+        Ok(CDSE {
+            http,
+            precache: PrecachePool::from_settings(storage.clone(), &settings.precache),
+            storage,
+            settings: settings.cdse.clone(),
             username: username.to_string(),
-            password: password.to_string()
+            password: password.to_string(),
+            downloads: SingleFlight::new(),
+            orders: Orders::default(),
+            renders: SingleFlight::new(),
+            decoded,
+        })
+    }
+
+    /// A fresh access token for the CDSE api
+    async fn token(&self) -> Result<String, CdseError> {
+        authenticate::authenticate(&self.http, &self.settings, self.username.as_str(), self.password.as_str()).await
+    }
+
+    /// Search the catalogue, giving the first page of products
+    pub async fn search(&self, query: CDSESearch) -> Result<Vec<SearchResult>, CdseError> {
+        Ok(self.search_up_to(query, PAGE_SIZE).await?.products)
+    }
+
+    /// Search the catalogue, following pages up to MAX_PRODUCTS products
+    pub async fn search_all(&self, query: CDSESearch) -> Result<Found, CdseError> {
+        self.search_up_to(query, MAX_PRODUCTS).await
+    }
+
+    async fn search_up_to(&self, query: CDSESearch, limit: usize) -> Result<Found, CdseError> {
+        let found = search::search(&self.http, self.settings.catalogue_url.as_str(), query, limit).await?;
+
+        self.orders.note_online(found.products.iter().map(|x| (x.id.clone(), x.online)), self.settings.order_poll_secs);
+
+        Ok(found)
+    }
+
+    /// A file from the local disk cache or the bucket
+    pub(crate) async fn check_bucket_and_download(&self, filename: &str) -> Option<Bytes> {
+        self.storage.get(filename).await
+    }
+
+    /// Store a file in the bucket (and the local disk cache)
+    pub(crate) async fn store(&self, filename: &str, data: Vec<u8>) {
+        self.storage.put(filename, data).await;
+    }
+
+    /// Tile metadata of a product, from the bucket if we have seen the product before
+    pub async fn metadata(&self, id: &str) -> Option<TileMetadata> {
+        let dir = id.to_owned() + "/metadata.json";
+
+        if let Some(data) = self.check_bucket_and_download(dir.as_str()).await {
+            if let Ok(metadata) = serde_json::from_slice(&data) {
+                return Some(metadata);
+            }
         }
+
+        let sat_data = self.sat_data(id).await.ok()?;
+        upload_metadata_to_bucket(&self.storage, id, &sat_data).await;
+
+        sat_data.metadata().cloned()
     }
 
-    async fn check_bucket_and_download(&self, filename: &str) -> Option<Bytes> {
-        let data_result = self.google_client.download_object(&GetObjectRequest {
-            bucket: "satellite-storage".to_string(),
-            object: filename.to_string(),
-            ..Default::default()
-        }, &Range::default()).await;
+    /// Make sure a product can be downloaded. Products in the long term archive are ordered the
+    /// first time they are asked for and are Pending until CDSE has them back. The catalogue is
+    /// asked at most once per poll interval, and not at all when a recent search said
+    async fn ensure_online(&self, id: &str) -> Result<(), CdseError> {
+        let interval = self.settings.order_poll_secs;
+        let pending = CdseError::Pending { id: id.to_string(), retry_after: interval };
 
-        if let Ok(data) = data_result {
-            Some(Bytes::from(data))
-        } else {
-            None
+        if self.orders.checked_within(id, interval) {
+            return Err(pending);
+        }
+
+        let catalogue_url = self.settings.catalogue_url.as_str();
+
+        let online = match self.orders.take_online(id, interval) {
+            Some(online) => online,
+            None => order::is_online(&self.http, catalogue_url, id).await?,
+        };
+
+        if online {
+            self.orders.remove(id);
+            return Ok(());
+        }
+
+        if !self.orders.is_ordered(id) {
+            let token = self.token().await?;
+            order::order(&self.http, catalogue_url, id, token.as_str()).await?;
+
+            info!("Ordered {id} from the long term archive");
+        }
+
+        self.orders.mark_checked(id);
+
+        Err(pending)
+    }
+
+    /// Check on every ordered product once per poll interval, downloading each to the bucket as
+    /// soon as it is back. Runs forever
+    pub async fn poll_orders(&self) {
+        loop {
+            tokio::time::sleep(std::time::Duration::from_secs(self.settings.order_poll_secs)).await;
+
+            for id in self.orders.ids() {
+                match self.zip(id.as_str()).await {
+                    Ok(_) => info!("{id} is back from the long term archive"),
+                    Err(CdseError::Pending { .. }) => {}
+                    Err(e) => warn!("Could not check on the order of {id}: {e}"),
+                }
+            }
+        }
+    }
+
+    /// The zip of a product, from the bucket or ESA. Concurrent calls for the same product share
+    /// one download
+    async fn zip(&self, id: &str) -> Result<ZipArchive<Cursor<Bytes>>, CdseError> {
+        self.downloads.run(id, async {
+            if let Some(zip) = download::cached(&self.storage, id).await {
+                return Ok(zip);
+            }
+
+            self.ensure_online(id).await?;
+
+            let token = self.token().await?;
+
+            download::download(&self.storage, &self.http, self.settings.catalogue_url.as_str(), id, token.as_str()).await
+        }).await
+    }
+
+    /// Download (or pull from the bucket) a product and decode its bands
+    pub async fn sat_data(&self, id: &str) -> Result<Arc<SatData>, CdseError> {
+        self.sat_data_at_scale(id, 1).await
+    }
+
+    /// A product with every band reduced by scale (1 or one of OVERVIEW_SCALES). Recently used
+    /// products are kept decoded in memory. Products that had to be loaded are handed to the
+    /// precache policy
+    pub async fn sat_data_at_scale(&self, id: &str, scale: u32) -> Result<Arc<SatData>, CdseError> {
+        let key = format!("{id}@{scale}");
+
+        if let Some(sat_data) = self.decoded.get(key.as_str()) {
+            return Ok(sat_data);
+        }
+
+        // shrinking the full product is quicker than going back to the zip
+        let (sat_data, loaded) = match self.decoded.get(format!("{id}@1").as_str()) {
+            Some(full) => (Arc::new(full.overview(scale)), false),
+            None => (Arc::new(self.load(id, scale).await?), true),
+        };
+
+        self.decoded.insert(key.as_str(), sat_data.clone());
+
+        if loaded {
+            self.precache.submit(id, sat_data.clone());
+        }
+
+        Ok(sat_data)
+    }
+
+    /// Decode a product at a scale. Overviews come from the bucket when they were made before,
+    /// otherwise the zip is decoded at the reduced size and the overview is stored for next time
+    async fn load(&self, id: &str, scale: u32) -> Result<SatData, CdseError> {
+        if scale == 1 {
+            // check if zip exits. if not, download
+            let zip = self.zip(id).await?;
+
+            // load to sat data
+            return spawn_blocking(move || SatData::new(zip)).await.unwrap()
+                .map_err(|e| CdseError::BadProduct { id: id.to_string(), reason: format!("{e:#}") });
+        }
+
+        let dir = format!("{id}/overviews/{scale}/");
+
+        // an overview is only used whole, anything missing from its manifest and the zip is read
+        let manifest: Option<Vec<String>> = self.check_bucket_and_download((dir.clone() + OVERVIEW_MANIFEST).as_str()).await
+            .and_then(|data| serde_json::from_slice(&data).ok());
+
+        let mut files = Vec::new();
+        for name in manifest.unwrap_or_default() {
+            match self.check_bucket_and_download((dir.clone() + name.as_str()).as_str()).await {
+                Some(data) => files.push((name, data)),
+                None => {
+                    files.clear();
+                    break;
+                }
+            }
+        }
+
+        if !files.is_empty() {
+            let metadata = self.check_bucket_and_download((id.to_owned() + "/metadata.json").as_str()).await
+                .and_then(|data| serde_json::from_slice(&data).ok());
+
+            if let Ok(sat_data) = spawn_blocking(move || SatData::from_encoded_bands(files, metadata, scale)).await.unwrap() {
+                return Ok(sat_data);
+            }
         }
+
+        let zip = self.zip(id).await?;
+        let sat_data = spawn_blocking(move || SatData::new_reduced(zip, scale)).await.unwrap()
+            .map_err(|e| CdseError::BadProduct { id: id.to_string(), reason: format!("{e:#}") })?;
+
+        let storage = self.storage.clone();
+        let id_clone = id.to_string();
+        let overview = Arc::new(sat_data.clone());
+
+        tokio::spawn(async move { upload_overview_to_bucket(&storage, id_clone.as_str(), overview).await });
+
+        Ok(sat_data)
+    }
+
+    pub(crate) fn storage(&self) -> &Storage {
+        &self.storage
     }
 
-    /// Return a image from an ID with a given filter and contrast
-    pub async fn fetch(&self, id: &str, filter: &str) -> Vec<u8> {
-        let dir = id.to_owned() + "/" + filter + ".jpg";
+    /// Remove cached renders and tiles of a product, of a filter, or of a filter for one product. Products,
+    /// overviews and metadata stay
+    pub(crate) async fn purge_renders(&self, id: Option<&str>, filter: Option<&str>) -> PurgeReport {
+        let prefix = id.map(|id| format!("{id}/")).unwrap_or_default();
+
+        index::purge(&self.storage, prefix.as_str(), |name| is_render(name, filter)).await
+    }
+
+    /// Names of everything in the bucket starting with prefix
+    pub(crate) async fn list(&self, prefix: &str) -> Vec<String> {
+        self.storage.list(prefix).await
+    }
+
+    /// Return a image from an ID with a given filter, reduced by scale (1 for full resolution) and
+    /// encoded as format
+    pub async fn fetch(&self, id: &str, filter: &str, scale: u32, format: Format) -> Result<Vec<u8>, CdseError> {
+        self.fetch_with_progress(id, filter, scale, format, &|_, _| {}).await
+    }
+
+    /// Same as fetch, calling progress with what it is doing and roughly how far along it is (0 to
+    /// 1) on the way
+    pub async fn fetch_with_progress(&self, id: &str, filter: &str, scale: u32, format: Format, progress: &(dyn Fn(&str, f64) + Sync)) -> Result<Vec<u8>, CdseError> {
+        let dir = image_cache_name(id, filter, scale, format);
 
         // check if filter exists
         let image_with_filter_result = self.check_bucket_and_download(dir.as_str()).await;
 
         // if image with filter does exist, return it
         if let Some(image) = image_with_filter_result {
-            image.to_vec()
+            Ok(image.to_vec())
         } else {
-            // check if zip exits. if not, download
-            let zip = download::download(&self.google_client, id, authenticate::authenticate(&self.cdse_client,self.username.as_str(),self.password.as_str()).as_str()).await;
+            // anyone else asking for the same image while it renders waits for this one
+            self.renders.run(dir.as_str(), async {
+                progress("loading product", 0.1);
+                let sat_data = self.sat_data_at_scale(id, scale).await?;
 
-            // load to sat data
-            let sat_data = SatData::new(zip).unwrap();
-
-            // default to true color for check
-            let m = if filter == "False Color" {
-                false_color(&sat_data)
-            } else if filter == "NDWI" {
-                ndwi(&sat_data)
-            } else if filter == "SWIR" {
-                swir(&sat_data)
-            } else {
-                true_color(&sat_data)
+                progress("rendering", 0.7);
+                let (owned_filter, owned_data) = (filter.to_string(), sat_data.clone());
+                let image = spawn_blocking(move || render(owned_filter.as_str(), &owned_data, format)).await.unwrap();
+
+                // the caller does not wait for the upload
+                let (storage, owned_dir, owned_image) = (self.storage.clone(), dir.clone(), image.clone());
+                tokio::spawn(async move { storage.put(owned_dir.as_str(), owned_image).await });
+
+                // return image
+                Ok(image)
+            }).await
+        }
+    }
+
+    /// A jpg preview of a product no bigger than max_size, made from the catalogue quicklook or
+    /// the product's own true colour image without downloading the whole product
+    pub async fn preview(&self, id: &str, source: PreviewSource, max_size: u32) -> Option<Vec<u8>> {
+        let dir = source.cache_name(id);
+
+        let data = if let Some(data) = self.check_bucket_and_download(dir.as_str()).await {
+            data
+        } else {
+            let catalogue_url = self.settings.catalogue_url.as_str();
+
+            let downloaded = match self.token().await {
+                Ok(token) => match source {
+                    PreviewSource::Quicklook => preview::quicklook(&self.http, catalogue_url, id, token.as_str()).await,
+                    PreviewSource::TrueColor => preview::true_color_image(&self.http, catalogue_url, id, token.as_str()).await,
+                },
+                Err(e) => Err(e.into()),
             };
 
-            // convert image to jpg
-            let mut buffer = Vector::new();
-            opencv::imgcodecs::imencode(".jpg", &m, &mut buffer, &Default::default()).unwrap();
+            let data = match downloaded {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("No {source:?} preview for {id}: {e}");
+                    return None;
+                }
+            };
 
-            let g_client = self.google_client.clone();
-            let id_clone = id.to_string();
+            self.store(dir.as_str(), data.to_vec()).await;
 
-            // precache images
-            thread::spawn(move || {
-                // upload
-                Runtime::new().unwrap().block_on(upload_image_to_bucket(&g_client, id_clone.as_str(), "True Color", &sat_data));
-                Runtime::new().unwrap().block_on(upload_image_to_bucket(&g_client, id_clone.as_str(), "False Color", &sat_data));
-                Runtime::new().unwrap().block_on(upload_image_to_bucket(&g_client, id_clone.as_str(), "NDWI", &sat_data));
-                Runtime::new().unwrap().block_on(upload_image_to_bucket(&g_client, id_clone.as_str(), "SWIR", &sat_data));
-            });
+            data
+        };
 
-            // return image
-            buffer.to_vec()
-        }
+        // the true colour image is full size, so only decode as much of it as we need
+        let flags = match source {
+            PreviewSource::TrueColor => match overview_for_size(max_size) {
+                1 => IMREAD_COLOR,
+                2 => IMREAD_REDUCED_COLOR_2,
+                4 => IMREAD_REDUCED_COLOR_4,
+                _ => IMREAD_REDUCED_COLOR_8,
+            },
+            PreviewSource::Quicklook => IMREAD_COLOR,
+        };
+
+        spawn_blocking(move || {
+            let image = opencv::imgcodecs::imdecode(&Vector::<u8>::from_slice(&data), flags).ok()?;
+
+            Some(Format::default().encode(&fit(&image, max_size)))
+        }).await.unwrap()
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,83 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::json;
+
+use crate::cdse::http::{CdseError, CdseHttp, Endpoint};
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// Whether a product can be downloaded straight away, or is in the long term archive
+pub async fn is_online(http: &CdseHttp, catalogue_url: &str, id: &str) -> Result<bool, CdseError> {
+    let url = format!("{catalogue_url}/Products({id})");
+    let product = http.json(Endpoint::Catalogue, || http.client().get(url.as_str())).await?;
+
+    // products the catalogue says nothing about are taken to be online, the download will tell
+    Ok(product["Online"].as_bool().unwrap_or(true))
+}
+
+/// Ask CDSE to bring a product back from the long term archive. Ordering a product that is already
+/// on its way back is not an error
+pub async fn order(http: &CdseHttp, catalogue_url: &str, id: &str, token: &str) -> Result<(), CdseError> {
+    let url = format!("{catalogue_url}/Products({id})/OData.CSC.Order");
+
+    match http.send(Endpoint::Catalogue, || http.client().post(url.as_str()).bearer_auth(token).json(&json!({}))).await {
+        Err(CdseError::Rejected { status: 409, .. }) => Ok(()),
+        other => other,
+    }
+}
+
+/// Products ordered from the long term archive that are not back yet, with when we last asked the
+/// catalogue about each. Only kept in memory, after a restart a product is simply ordered again.
+/// Searches tell us whether each product they found is online too, which saves asking the
+/// catalogue again when one of them is fetched soon after
+#[derive(Default)]
+pub struct Orders {
+    checked: Mutex<HashMap<String, u64>>,
+    /// Online flags from searches, with when the search was made
+    found: Mutex<HashMap<String, (bool, u64)>>,
+}
+
+impl Orders {
+    /// Keep the online flag of products a search found. Flags older than max_age are dropped
+    pub fn note_online(&self, products: impl Iterator<Item = (String, bool)>, max_age: u64) {
+        let time = now();
+        let mut found = self.found.lock().unwrap();
+
+        found.retain(|_, (_, noted)| time.saturating_sub(*noted) < max_age);
+        found.extend(products.map(|(id, online)| (id, (online, time))));
+    }
+
+    /// The online flag a search gave for a product less than max_age seconds ago. It is only used
+    /// once, after that the catalogue is asked
+    pub fn take_online(&self, id: &str, max_age: u64) -> Option<bool> {
+        self.found.lock().unwrap().remove(id)
+            .filter(|(_, noted)| now().saturating_sub(*noted) < max_age)
+            .map(|(online, _)| online)
+    }
+
+    pub fn is_ordered(&self, id: &str) -> bool {
+        self.checked.lock().unwrap().contains_key(id)
+    }
+
+    /// Whether the product was ordered and looked at less than interval seconds ago, so asking
+    /// again is not worth it yet
+    pub fn checked_within(&self, id: &str, interval: u64) -> bool {
+        self.checked.lock().unwrap().get(id).is_some_and(|checked| now().saturating_sub(*checked) < interval)
+    }
+
+    pub fn mark_checked(&self, id: &str) {
+        self.checked.lock().unwrap().insert(id.to_string(), now());
+    }
+
+    pub fn remove(&self, id: &str) {
+        self.checked.lock().unwrap().remove(id);
+    }
+
+    pub fn ids(&self) -> Vec<String> {
+        self.checked.lock().unwrap().keys().cloned().collect()
+    }
+}
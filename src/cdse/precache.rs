@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::sync::Arc;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Mutex;
+use tokio::task::spawn_blocking;
+
+use crate::cache::Storage;
+use crate::cdse::{image_cache_name, render, upload_metadata_to_bucket, upload_overview_to_bucket};
+use crate::encoding::Format;
+use crate::filters::sar::SarFilter;
+use crate::sat_data::{overview_for_size, SatData, OVERVIEW_MANIFEST, OVERVIEW_SCALES};
+use crate::settings::PrecacheSettings;
+
+/// What to render ahead of time once a product has been decoded
+#[derive(Debug, Clone)]
+pub struct PrecachePolicy {
+    pub filters: Vec<String>,
+    pub formats: Vec<Format>,
+    /// Scales to render at, 1 being full size
+    pub scales: Vec<u32>,
+    /// Store every overview level whenever a full size product is decoded
+    pub overviews: bool,
+}
+
+impl PrecachePolicy {
+    /// Render nothing ahead of time
+    pub fn none() -> PrecachePolicy {
+        PrecachePolicy {
+            filters: Vec::new(),
+            formats: Vec::new(),
+            scales: Vec::new(),
+            overviews: false,
+        }
+    }
+
+    /// Policy from the settings, which have been checked already
+    pub fn from_settings(settings: &PrecacheSettings) -> PrecachePolicy {
+        if settings.filters.is_empty() {
+            return PrecachePolicy::none();
+        }
+
+        let formats = settings.formats.iter()
+            .filter_map(|x| Format::parse(x).ok())
+            .collect();
+
+        let mut scales: Vec<u32> = settings.sizes.iter()
+            .filter_map(|x| if x == "full" { Some(1) } else { x.parse().ok().map(overview_for_size) })
+            .collect();
+        scales.sort();
+        scales.dedup();
+
+        PrecachePolicy {
+            filters: settings.filters.clone(),
+            formats,
+            scales,
+            overviews: settings.overviews,
+        }
+    }
+}
+
+/// Fixed number of tasks working through decoded products. Queued products hold on to their bands
+/// until a worker gets to them, whether or not the memory cache kept them, so the queue is what
+/// bounds the memory precaching takes. When it is full new products are dropped, precaching is
+/// only ever a head start.
+pub struct PrecachePool {
+    sender: Sender<(String, Arc<SatData>)>,
+}
+
+/// Run one step on its own task, logging instead of taking the worker down when it fails
+async fn attempt<F: Future<Output = ()> + Send + 'static>(what: &str, id: &str, step: F) {
+    if tokio::spawn(step).await.is_err() {
+        error!("Precaching {what} of {id} failed");
+    }
+}
+
+async fn run(storage: &Storage, policy: &PrecachePolicy, id: String, sat_data: Arc<SatData>) {
+    let scale = sat_data.scale();
+
+    // the policy runs on every decode, most of it is usually in the bucket from the last one
+    let stored: HashSet<String> = storage.list(format!("{id}/").as_str()).await.into_iter().collect();
+
+    if !stored.contains(format!("{id}/metadata.json").as_str()) {
+        let (storage, id, sat_data) = (storage.clone(), id.clone(), sat_data.clone());
+        attempt("metadata", id.clone().as_str(), async move { upload_metadata_to_bucket(&storage, id.as_str(), &sat_data).await }).await;
+    }
+
+    // we can only shrink what we have
+    for target in policy.scales.iter().copied().filter(|s| *s >= scale) {
+        // optical filters would all draw the same composite of a radar product, and radar ones
+        // have nothing to draw from an optical product
+        let missing: Vec<(&String, Format)> = policy.filters.iter()
+            .filter(|f| SarFilter::from_name(f).is_some() == sat_data.sar().is_some())
+            .flat_map(|f| policy.formats.iter().map(move |format| (f, *format)))
+            .filter(|(f, format)| !stored.contains(image_cache_name(id.as_str(), f, target, *format).as_str()))
+            .collect();
+
+        if missing.is_empty() {
+            continue;
+        }
+
+        let scaled = if target == scale {
+            sat_data.clone()
+        } else {
+            let full = sat_data.clone();
+            Arc::new(spawn_blocking(move || full.overview(target)).await.unwrap())
+        };
+
+        for (filter, format) in missing {
+            let (owned_filter, owned_data) = (filter.clone(), scaled.clone());
+            let image = match spawn_blocking(move || render(owned_filter.as_str(), &owned_data, format)).await {
+                Ok(image) => image,
+                Err(_) => {
+                    error!("Precaching {filter} of {id} failed");
+                    continue;
+                }
+            };
+
+            let (storage, name) = (storage.clone(), image_cache_name(id.as_str(), filter, target, format));
+            attempt(filter, id.as_str(), async move { storage.put(name.as_str(), image).await }).await;
+        }
+    }
+
+    // a full resolution decode is the cheapest time to make every overview
+    if policy.overviews && scale == 1 {
+        for overview_scale in OVERVIEW_SCALES {
+            if stored.contains(format!("{id}/overviews/{overview_scale}/{OVERVIEW_MANIFEST}").as_str()) {
+                continue;
+            }
+
+            let full = sat_data.clone();
+            let overview = match spawn_blocking(move || full.overview(overview_scale)).await {
+                Ok(overview) => Arc::new(overview),
+                Err(_) => continue,
+            };
+
+            let (storage, id) = (storage.clone(), id.clone());
+            attempt("overviews", id.clone().as_str(), async move { upload_overview_to_bucket(&storage, id.as_str(), overview).await }).await;
+        }
+    }
+}
+
+impl PrecachePool {
+    /// Start the workers, on the runtime this is called from
+    pub fn new(storage: Storage, policy: PrecachePolicy, workers: usize, queue: usize) -> PrecachePool {
+        let (sender, receiver) = channel(queue);
+        let receiver: Arc<Mutex<Receiver<(String, Arc<SatData>)>>> = Arc::new(Mutex::new(receiver));
+        let policy = Arc::new(policy);
+
+        for _ in 0..workers.max(1) {
+            let receiver = receiver.clone();
+            let storage = storage.clone();
+            let policy = policy.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    let (id, sat_data) = match receiver.lock().await.recv().await {
+                        Some(task) => task,
+                        None => break,
+                    };
+
+                    run(&storage, &policy, id, sat_data).await;
+                }
+            });
+        }
+
+        PrecachePool { sender }
+    }
+
+    /// Pool sized and run by the settings
+    pub fn from_settings(storage: Storage, settings: &PrecacheSettings) -> PrecachePool {
+        PrecachePool::new(storage, PrecachePolicy::from_settings(settings), settings.workers, settings.queue)
+    }
+
+    /// Queue a product that was just decoded
+    pub fn submit(&self, id: &str, sat_data: Arc<SatData>) {
+        match self.sender.try_send((id.to_string(), sat_data)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => warn!("Precache queue is full, skipping {id}"),
+            Err(TrySendError::Closed(_)) => error!("Precache workers have stopped, skipping {id}"),
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use anyhow::Error;
+use bytes::Bytes;
+
+use crate::cdse::http::{CdseHttp, Endpoint};
+
+/// Where a preview comes from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreviewSource {
+    /// The small jpg the catalogue keeps for every product, a few hundred pixels across
+    Quicklook,
+    /// The true colour jp2 inside the product, full resolution
+    TrueColor,
+}
+
+impl PreviewSource {
+    /// "quicklook" or "tci"
+    pub fn from_name(name: &str) -> Option<PreviewSource> {
+        match name.to_lowercase().as_str() {
+            "quicklook" | "ql" => Some(PreviewSource::Quicklook),
+            "tci" | "true color" => Some(PreviewSource::TrueColor),
+            _ => None,
+        }
+    }
+
+    /// Where the preview is kept in the bucket
+    pub fn cache_name(&self, id: &str) -> String {
+        match self {
+            PreviewSource::Quicklook => format!("{id}/preview/quicklook.jpg"),
+            PreviewSource::TrueColor => format!("{id}/preview/TCI.jp2"),
+        }
+    }
+}
+
+/// Names of the entries in a folder of the product
+async fn list_nodes(http: &CdseHttp, path: &str, token: &str) -> anyhow::Result<Vec<String>> {
+    let url = format!("{path}/Nodes");
+    let response = http.json(Endpoint::Catalogue, || http.client().get(url.as_str()).bearer_auth(token)).await?;
+
+    Ok(response["result"].as_array()
+        .ok_or_else(|| Error::msg(format!("No nodes under {path}")))?
+        .iter()
+        .filter_map(|node| node["Name"].as_str().map(str::to_string))
+        .collect())
+}
+
+/// First entry in a folder whose name ends with one of the endings
+async fn find_node(http: &CdseHttp, path: &str, endings: &[&str], token: &str) -> anyhow::Result<String> {
+    list_nodes(http, path, token).await?
+        .into_iter()
+        .find(|name| endings.iter().any(|ending| name.ends_with(ending)))
+        .ok_or_else(|| Error::msg(format!("Nothing ending in {endings:?} under {path}")))
+}
+
+/// The quicklook of a product. The catalogue lists it as an asset, older products only have the
+/// "-ql.jpg" in the root of the SAFE folder
+pub async fn quicklook(http: &CdseHttp, catalogue_url: &str, id: &str, token: &str) -> anyhow::Result<Bytes> {
+    let url = format!("{catalogue_url}/Products({id})?$expand=Assets");
+    let product = http.json(Endpoint::Catalogue, || http.client().get(url.as_str())).await?;
+
+    let asset_link = product["Assets"].as_array()
+        .and_then(|assets| assets.iter().find(|asset| asset["Type"].as_str() == Some("QUICKLOOK")))
+        .and_then(|asset| asset["DownloadLink"].as_str());
+
+    if let Some(link) = asset_link {
+        return Ok(http.get_with_token(Endpoint::Download, link, token).await?);
+    }
+
+    let product_path = format!("{catalogue_url}/Products({id})");
+    let safe = find_node(http, product_path.as_str(), &[".SAFE"], token).await?;
+
+    let safe_path = format!("{product_path}/Nodes({safe})");
+    let ql = find_node(http, safe_path.as_str(), &["-ql.jpg"], token).await?;
+
+    Ok(http.get_with_token(Endpoint::Download, format!("{safe_path}/Nodes({ql})/$value").as_str(), token).await?)
+}
+
+/// The true colour image of a product, walking the SAFE folder to the one jp2 rather than
+/// downloading the whole zip. Level 2A products keep it with the other 10 m bands
+pub async fn true_color_image(http: &CdseHttp, catalogue_url: &str, id: &str, token: &str) -> anyhow::Result<Bytes> {
+    let product_path = format!("{catalogue_url}/Products({id})");
+    let safe = find_node(http, product_path.as_str(), &[".SAFE"], token).await?;
+
+    let granules_path = format!("{product_path}/Nodes({safe})/Nodes(GRANULE)");
+    let granule = list_nodes(http, granules_path.as_str(), token).await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| Error::msg("Product has no granule"))?;
+
+    let mut image_path = format!("{granules_path}/Nodes({granule})/Nodes(IMG_DATA)");
+    let image_nodes = list_nodes(http, image_path.as_str(), token).await?;
+
+    if image_nodes.iter().any(|name| name == "R10m") {
+        image_path.push_str("/Nodes(R10m)");
+    }
+
+    let tci = find_node(http, image_path.as_str(), &["_TCI.jp2", "_TCI_10m.jp2"], token).await?;
+
+    Ok(http.get_with_token(Endpoint::Download, format!("{image_path}/Nodes({tci})/$value").as_str(), token).await?)
+}
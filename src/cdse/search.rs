@@ -1,74 +1,222 @@
-use std::io::Read;
+use rocket::http::RawStr;
+
+use crate::cdse::http::{CdseError, CdseHttp, Endpoint};
 
 use crate::cdse::search_result::{parse_search_result, SearchResult};
+use crate::geo::parse_polygons;
+
+/// Products asked for per catalogue page
+pub const PAGE_SIZE: usize = 100;
+
+/// Products a search that follows every page stops at. A time series over that many is already
+/// too slow to wait for
+pub const MAX_PRODUCTS: usize = 1000;
+
+/// What a search found, newest first
+#[derive(Debug, Clone)]
+pub struct Found {
+    pub products: Vec<SearchResult>,
+    /// Whether the catalogue had more products than the search was allowed to page through
+    pub truncated: bool,
+}
 
 #[derive(Debug)]
 pub struct CDSESearch {
     pub satellite: Option<String>,
     pub geojson: Option<serde_json::Value>,
     pub max_cloud_cover: Option<f64>,
+    /// Only products sensed on or after this date (YYYY-MM-DD or a full ISO time)
+    pub start_date: Option<String>,
+    /// Only products sensed on or before this date (YYYY-MM-DD or a full ISO time)
+    pub end_date: Option<String>,
+    /// Sentinel 1 only, polarisation channels like "VV&VH" or "VV"
+    pub polarisation: Option<String>,
+    /// Sentinel 1 only, acquisition mode like "IW" or "EW"
+    pub mode: Option<String>,
 }
 
-fn parse_geojson_to_odata(json: serde_json::Value) -> String {
-    // get intersect type
-    let t = json["features"][0]["geometry"]["type"].as_str().unwrap();
-
-    // get coordinates
-    let coords = json["features"][0]["geometry"]["coordinates"][0].as_array().unwrap();
+/// Read digits only, so signs and spaces are not taken for part of a number
+fn digits(text: &str) -> Option<u32> {
+    if text.is_empty() || !text.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
 
-    // build string
-    let mut to_return = t.to_uppercase() + "((";
+    text.parse().ok()
+}
 
-    for x in coords {
-        to_return.push_str((x[0].as_f64().unwrap().to_string() + " " + x[1].as_f64().unwrap().to_string().as_str() + ",").as_str());
+/// Whether text is a day of the calendar written as YYYY-MM-DD
+fn is_date(text: &str) -> bool {
+    if text.len() != 10 || !text.is_ascii() || &text[4..5] != "-" || &text[7..8] != "-" {
+        return false;
     }
 
-    // remove last comma
-    to_return = to_return[..to_return.len() - 1].to_string();
+    let (Some(year), Some(month), Some(day)) = (digits(&text[0..4]), digits(&text[5..7]), digits(&text[8..10])) else {
+        return false;
+    };
 
-    // add odata ending
-    to_return.push_str("))");
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        1..=12 => 31,
+        _ => return false,
+    };
 
-    to_return
+    (1..=days).contains(&day)
 }
 
-/// Given certain search criteria, we can filter what data we see
-pub fn search(cdsesearch: CDSESearch) -> Vec<SearchResult> {
-    // create client data to
-    let client = reqwest::blocking::Client::new();
-    let mut buffer = String::new();
+/// Whether text is the time part of an RFC 3339 time, like 10:15:30Z, 10:15:30.25+02:00
+fn is_time(text: &str) -> bool {
+    // hours, minutes and seconds of a time, or hours and minutes of an offset, two digits each
+    let clock = |part: &str, fields: usize| {
+        let values: Option<Vec<u32>> = part.split(':').map(digits).collect();
+
+        match values.as_deref() {
+            Some(values) if values.len() == fields && part.len() == fields * 3 - 1 => {
+                // 60 seconds for leap seconds
+                values[0] < 24 && values[1] < 60 && values.get(2).map_or(true, |seconds| *seconds <= 60)
+            }
+            _ => false,
+        }
+    };
+
+    let (time, offset) = match text.strip_suffix(['Z', 'z']) {
+        Some(time) => (time, None),
+        None => match text.rfind(['+', '-']) {
+            Some(at) => (&text[..at], Some(&text[at + 1..])),
+            None => return false,
+        },
+    };
+
+    let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+
+    clock(time, 3) && digits(fraction).is_some() && offset.map_or(true, |offset| clock(offset, 2))
+}
+
+/// Turn a plain date into the time OData expects. Full RFC 3339 times are passed along as they are
+fn to_odata_time(date: &str, end_of_day: bool) -> Result<String, CdseError> {
+    let valid = match date.split_once(['T', 't']) {
+        Some((day, time)) => is_date(day) && is_time(time),
+        None => is_date(date),
+    };
+
+    if !valid {
+        return Err(CdseError::BadRequest { reason: format!("{date} is not a date (YYYY-MM-DD) or an RFC 3339 time") });
+    }
+
+    Ok(if date.len() != 10 {
+        // a + would be read as a space in the query string
+        date.replace('+', "%2B")
+    } else if end_of_day {
+        format!("{date}T23:59:59.999Z")
+    } else {
+        format!("{date}T00:00:00.000Z")
+    })
+}
+
+/// A value for inside an OData string literal in the query string. Quotes are doubled, as OData
+/// escapes them, and anything that would end or change the query parameter is percent encoded
+fn odata_string(value: &str) -> String {
+    RawStr::new(value.replace('\'', "''").as_str()).percent_encode().to_string()
+}
 
+/// The area of a GeoJSON (anything geo::parse_polygons takes) as an OData condition. Every ring is
+/// its own footprint, which for holes only means they count as inside. None when there is no
+/// point or polygon in it
+fn area_to_odata(geojson: &serde_json::Value) -> Option<String> {
+    let footprints: Vec<String> = parse_polygons(geojson).iter()
+        .flat_map(|polygon| polygon.rings.iter())
+        .filter(|ring| !ring.is_empty())
+        .map(|ring| {
+            let corners: Vec<String> = ring.iter().map(|(x, y)| format!("{x} {y}")).collect();
+
+            let shape = if corners.len() == 1 {
+                format!("POINT({})", corners[0])
+            } else {
+                format!("POLYGON(({}))", corners.join(","))
+            };
+
+            format!("OData.CSC.Intersects(area=geography'SRID=4326;{shape}')")
+        })
+        .collect();
+
+    if footprints.is_empty() {
+        None
+    } else {
+        Some(format!("({})", footprints.join(" or ")))
+    }
+}
+
+/// Given certain search criteria, we can filter what data we see. Pages are followed until limit
+/// products have been found
+pub async fn search(http: &CdseHttp, catalogue_url: &str, cdsesearch: CDSESearch, limit: usize) -> Result<Found, CdseError> {
     // build request url
-    let mut url = "https://catalogue.dataspace.copernicus.eu/odata/v1/Products?$filter=".to_string();
+    let mut url = format!("{catalogue_url}/Products?$filter=");
+
+    if let Some(satellite) = cdsesearch.satellite {
+        url.push_str(format!("Collection/Name eq '{}' and ", odata_string(satellite.as_str())).as_str());
 
-    if cdsesearch.satellite.is_some() {
-        url.push_str(format!("Collection/Name eq '{}' and ", cdsesearch.satellite.unwrap()).as_str());
+        // GRD is the only Sentinel 1 product we can draw
+        if satellite == "SENTINEL-1" {
+            url.push_str("contains(Name,'_GRD') and ");
+        }
     }
 
-    if cdsesearch.geojson.is_some() {
-        let geojsoned = parse_geojson_to_odata(cdsesearch.geojson.unwrap());
+    if let Some(polarisation) = cdsesearch.polarisation {
+        // "VV&VH", where & would end the query parameter
+        let polarisation = odata_string(polarisation.to_uppercase().as_str());
 
-        url.push_str(format!("OData.CSC.Intersects(area=geography'SRID=4326;{}') and ", geojsoned).as_str());
+        url.push_str(format!("Attributes/OData.CSC.StringAttribute/any(att:att/Name eq 'polarisationChannels' and att/OData.CSC.StringAttribute/Value eq '{}') and ", polarisation).as_str());
+    }
+
+    if let Some(mode) = cdsesearch.mode {
+        url.push_str(format!("Attributes/OData.CSC.StringAttribute/any(att:att/Name eq 'operationalMode' and att/OData.CSC.StringAttribute/Value eq '{}') and ", odata_string(mode.to_uppercase().as_str())).as_str());
+    }
+
+    if let Some(geojson) = cdsesearch.geojson {
+        let area = area_to_odata(&geojson)
+            .ok_or_else(|| CdseError::BadRequest { reason: "GeoJson has no point or polygon in it".to_string() })?;
+
+        url.push_str(format!("{area} and ").as_str());
     }
 
     if cdsesearch.max_cloud_cover.is_some() {
         url.push_str(format!("Attributes/OData.CSC.DoubleAttribute/any(att:att/Name eq 'cloudCover' and att/OData.CSC.DoubleAttribute/Value le {}) and ", cdsesearch.max_cloud_cover.unwrap()).as_str());
     }
 
+    if let Some(start_date) = cdsesearch.start_date {
+        url.push_str(format!("ContentDate/Start ge {} and ", to_odata_time(start_date.as_str(), false)?).as_str());
+    }
+
+    if let Some(end_date) = cdsesearch.end_date {
+        url.push_str(format!("ContentDate/Start le {} and ", to_odata_time(end_date.as_str(), true)?).as_str());
+    }
+
     // remove and at the end
     url = url[0..url.len() - 5].to_string();
 
-    // add that we want to sort newest to oldest
-    url.push_str("&$orderby=ContentDate/Start desc");
+    // add that we want to sort newest to oldest, a page at a time
+    url.push_str(format!("&$orderby=ContentDate/Start desc&$top={}", PAGE_SIZE.min(limit)).as_str());
+
+    let mut products = Vec::new();
 
-    let mut res = client
-        .get(url)
-        .send()
-        .unwrap();
+    loop {
+        let page = http.json(Endpoint::Catalogue, || http.client().get(url.as_str())).await?;
 
-    res.read_to_string(&mut buffer).unwrap();
+        match page.get("value") {
+            Some(value) => products.extend(parse_search_result(value.clone())?),
+            None => return Err(CdseError::BadResponse { endpoint: Endpoint::Catalogue, reason: "search has no results list".to_string() }),
+        }
 
-    let to_return: serde_json::Value = serde_json::from_str(buffer.as_str()).unwrap();
+        url = match page["@odata.nextLink"].as_str() {
+            Some(next) if products.len() < limit => next.to_string(),
+            next => {
+                let truncated = products.len() > limit || next.is_some();
+                products.truncate(limit);
 
-    parse_search_result(to_return.get("value").unwrap().clone())
+                return Ok(Found { products, truncated });
+            }
+        };
+    }
 }
\ No newline at end of file
@@ -1,31 +1,54 @@
+use crate::cdse::http::{CdseError, Endpoint};
+
 #[derive(Debug, Clone)]
 pub struct SearchResult {
     pub id: String,
+    pub name: String,
+    /// When sensing started, as an ISO time
+    pub date: String,
     pub file_size: usize,
     pub online: bool,
     pub num_points: usize,
 }
 
 impl SearchResult {
-    pub fn new(json: &serde_json::Value) -> SearchResult {
-        SearchResult {
-            id: json["Id"].as_str().unwrap().to_string(),
-            file_size: json["ContentLength"].as_u64().unwrap() as usize,
-            online: json["Online"].as_bool().unwrap(),
-            num_points: json["GeoFootprint"]["coordinates"][0].as_array().unwrap().len(),
-        }
+    /// Read a product of a catalogue search. Products missing a field we rely on are a bad
+    /// response
+    pub fn new(json: &serde_json::Value) -> Result<SearchResult, CdseError> {
+        let missing = |field: &str| CdseError::BadResponse { endpoint: Endpoint::Catalogue, reason: format!("search result has no {field}") };
+
+        Ok(SearchResult {
+            id: json["Id"].as_str().ok_or_else(|| missing("Id"))?.to_string(),
+            name: json["Name"].as_str().unwrap_or_default().to_string(),
+            date: json["ContentDate"]["Start"].as_str().unwrap_or_default().to_string(),
+            file_size: json["ContentLength"].as_u64().ok_or_else(|| missing("ContentLength"))? as usize,
+            online: json["Online"].as_bool().ok_or_else(|| missing("Online"))?,
+            num_points: json["GeoFootprint"]["coordinates"][0].as_array().ok_or_else(|| missing("GeoFootprint"))?.len(),
+        })
+    }
+
+    /// The tile this product covers, taken from a name like
+    /// S2A_MSIL1C_20231010T101031_N0509_R022_T32TQM_20231010T130215.SAFE
+    pub fn tile_id(&self) -> Option<&str> {
+        self.name
+            .split('_')
+            .find(|part| part.len() == 6 && part.starts_with('T') && part[1..3].chars().all(|c| c.is_ascii_digit()))
+            .map(|part| &part[1..])
     }
 }
 
 /// Pass the json array here and this will parse it into SearchResult structs
-pub fn parse_search_result(json: serde_json::Value) -> Vec<SearchResult> {
+pub fn parse_search_result(json: serde_json::Value) -> Result<Vec<SearchResult>, CdseError> {
     let mut to_return = Vec::new();
 
-    for x in json.as_array().unwrap() {
+    let products = json.as_array()
+        .ok_or_else(|| CdseError::BadResponse { endpoint: Endpoint::Catalogue, reason: "search results are not a list".to_string() })?;
+
+    for x in products {
         to_return.push(
-            SearchResult::new(x)
+            SearchResult::new(x)?
         );
     }
 
-    to_return
+    Ok(to_return)
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+
+/// Makes concurrent calls for the same key share one piece of work. The first caller does the work
+/// and everyone waiting on that key gets a clone of its result. Once it finishes the key is
+/// forgotten, so later calls start fresh (and usually find the result cached in the bucket).
+///
+/// If the caller doing the work panics, one of the waiters takes over.
+pub struct SingleFlight<T> {
+    in_flight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> SingleFlight<T> {
+        SingleFlight {
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn run<F: Future<Output = T>>(&self, key: &str, work: F) -> T {
+        let cell = self.in_flight.lock().unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .clone();
+
+        let value = cell.get_or_init(|| work).await.clone();
+
+        // only remove our own cell, a newer one may have taken its place already
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            in_flight.remove(key);
+        }
+
+        value
+    }
+}
+
+impl<T: Clone> Default for SingleFlight<T> {
+    fn default() -> SingleFlight<T> {
+        SingleFlight::new()
+    }
+}
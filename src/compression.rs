@@ -0,0 +1,162 @@
+use std::io::{Read, Write};
+
+use flate2::write::GzEncoder;
+use rocket::http::{ContentType, Status};
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use xz2::read::XzEncoder;
+
+use crate::settings::Settings;
+
+/// Content codings we can send, in the order we prefer them when the client likes several equally
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Coding {
+    Zstd,
+    Brotli,
+    Gzip,
+    /// The old XZ payloads, only sent to clients that name it
+    Xz,
+    Identity,
+}
+
+const PREFERENCE: [Coding; 5] = [Coding::Zstd, Coding::Brotli, Coding::Gzip, Coding::Xz, Coding::Identity];
+
+impl Coding {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Coding::Zstd => "zstd",
+            Coding::Brotli => "br",
+            Coding::Gzip => "gzip",
+            Coding::Xz => "xz",
+            Coding::Identity => "identity",
+        }
+    }
+
+    /// Compress with this coding. Only XZ takes a level, the others use our usual ones
+    pub fn compress(&self, data: Vec<u8>, xz_level: u32) -> Vec<u8> {
+        match self {
+            Coding::Zstd => zstd::encode_all(data.as_slice(), 3).unwrap(),
+            Coding::Brotli => {
+                let mut c = Vec::new();
+                {
+                    let mut writer = brotli::CompressorWriter::new(&mut c, 4096, 5, 22);
+                    writer.write_all(&data).unwrap();
+                }
+                c
+            }
+            Coding::Gzip => {
+                let mut writer = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                writer.write_all(&data).unwrap();
+                writer.finish().unwrap()
+            }
+            Coding::Xz => {
+                let mut c = Vec::new();
+                XzEncoder::new(data.as_slice(), xz_level).read_to_end(&mut c).unwrap();
+                c
+            }
+            Coding::Identity => data,
+        }
+    }
+}
+
+/// Pick the coding from an Accept-Encoding header. The highest q value wins, "*" stands for any of
+/// the standard codings and no header at all means identity
+pub fn negotiate(accept_encoding: Option<&str>) -> Coding {
+    let mut best = (Coding::Identity, 0.0);
+
+    for entry in accept_encoding.unwrap_or_default().split(',') {
+        let mut parts = entry.split(';');
+        let name = parts.next().unwrap_or_default().trim().to_lowercase();
+
+        let q = parts
+            .filter_map(|p| p.trim().strip_prefix("q="))
+            .find_map(|q| q.parse::<f64>().ok())
+            .unwrap_or(1.0);
+
+        if q <= 0.0 {
+            continue;
+        }
+
+        let matches: Vec<Coding> = if name == "*" {
+            vec![Coding::Zstd, Coding::Brotli, Coding::Gzip]
+        } else {
+            PREFERENCE.iter().filter(|c| c.name() == name).copied().collect()
+        };
+
+        for coding in matches {
+            let rank = |c: Coding| PREFERENCE.iter().position(|x| *x == c).unwrap();
+
+            if q > best.1 || (q == best.1 && rank(coding) < rank(best.0)) {
+                best = (coding, q);
+            }
+        }
+    }
+
+    best.0
+}
+
+/// A response body compressed to suit the client's Accept-Encoding. Formats that are compressed
+/// already (jpg, png, webp) go out as they are unless the client asked for XZ by name
+pub struct Encoded {
+    pub content_type: ContentType,
+    pub body: Vec<u8>,
+    pub status: Status,
+    /// Sent along as they are
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl Encoded {
+    pub fn new(content_type: ContentType, body: Vec<u8>) -> Encoded {
+        Encoded { content_type, body, status: Status::Ok, headers: Vec::new() }
+    }
+
+    pub fn json(body: Vec<u8>) -> Encoded {
+        Encoded::new(ContentType::JSON, body)
+    }
+
+    /// JSON for a request that was taken on but is not done yet, to be asked again in retry_after
+    /// seconds
+    pub fn accepted(body: Vec<u8>, retry_after: u64) -> Encoded {
+        Encoded::json(body).with_status(Status::Accepted).with_header("Retry-After", retry_after.to_string())
+    }
+
+    pub fn with_status(self, status: Status) -> Encoded {
+        Encoded { status, ..self }
+    }
+
+    pub fn with_header(mut self, name: &'static str, value: String) -> Encoded {
+        self.headers.push((name, value));
+        self
+    }
+}
+
+impl<'r> Responder<'r, 'static> for Encoded {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        let mut coding = negotiate(request.headers().get_one("Accept-Encoding"));
+
+        let already_compressed = self.content_type == ContentType::JPEG
+            || self.content_type == ContentType::PNG
+            || self.content_type == ContentType::WEBP;
+
+        if already_compressed && coding != Coding::Xz {
+            coding = Coding::Identity;
+        }
+
+        let xz_level = request.rocket().state::<Settings>().map_or(9, |s| s.render.xz_level);
+
+        let mut builder = Response::build_from(coding.compress(self.body, xz_level).respond_to(request)?);
+        builder.status(self.status);
+        builder.header(self.content_type);
+        builder.raw_header("Vary", "Accept-Encoding");
+
+        for (name, value) in self.headers {
+            builder.raw_header(name, value);
+        }
+
+        if coding != Coding::Identity {
+            builder.raw_header("Content-Encoding", coding.name());
+        }
+
+        builder.ok()
+    }
+}
@@ -0,0 +1,117 @@
+use std::fmt::{Debug, Formatter};
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use google_cloud_storage::client::{Client, ClientConfig};
+use google_cloud_storage::http::objects::download::Range;
+use google_cloud_storage::http::objects::get::GetObjectRequest;
+use serde::Deserialize;
+
+use crate::settings::{CredentialProvider, Settings};
+
+/// CDSE login. Never printed, Debug leaves the values out
+#[derive(Clone)]
+pub struct Credentials {
+    pub username: String,
+    pub password: String,
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Credentials { .. }")
+    }
+}
+
+/// Layout of a key file, the same whether it is local or in the bucket
+#[derive(Deserialize)]
+struct Keys {
+    cdse: CDSEKeys,
+}
+
+#[derive(Deserialize)]
+struct CDSEKeys {
+    username: String,
+    password: String,
+}
+
+impl From<Keys> for Credentials {
+    fn from(keys: Keys) -> Credentials {
+        Credentials {
+            username: keys.cdse.username,
+            password: keys.cdse.password,
+        }
+    }
+}
+
+/// Parse a key file. The toml error is left out as it can quote the file
+fn parse_keys(contents: &str, from: &str) -> anyhow::Result<Credentials> {
+    let keys: Keys = toml::from_str(contents).map_err(|_| Error::msg(format!("{from} is not a valid key file")))?;
+
+    Ok(keys.into())
+}
+
+/// CDSE_USERNAME and CDSE_PASSWORD
+fn from_env() -> anyhow::Result<Credentials> {
+    Ok(Credentials {
+        username: std::env::var("CDSE_USERNAME").context("CDSE_USERNAME is not set")?,
+        password: std::env::var("CDSE_PASSWORD").context("CDSE_PASSWORD is not set")?,
+    })
+}
+
+fn from_file(path: &Path) -> anyhow::Result<Credentials> {
+    let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read {}", path.display()))?;
+
+    parse_keys(contents.as_str(), path.display().to_string().as_str())
+}
+
+/// One file per value, the way docker and kubernetes mount secrets
+fn from_secrets_dir(dir: &Path) -> anyhow::Result<Credentials> {
+    let read = |name: &str| -> anyhow::Result<String> {
+        let path = dir.join(name);
+        let value = std::fs::read_to_string(&path).with_context(|| format!("Could not read {}", path.display()))?;
+
+        Ok(value.trim().to_string())
+    };
+
+    Ok(Credentials {
+        username: read("cdse_username")?,
+        password: read("cdse_password")?,
+    })
+}
+
+/// The key file kept in the bucket
+async fn from_bucket(bucket: &str, object: &str) -> anyhow::Result<Credentials> {
+    let config = ClientConfig::default().with_auth().await.context("Could not authenticate with google")?;
+    let client = Client::new(config);
+
+    let file = client.download_object(&GetObjectRequest {
+        bucket: bucket.to_string(),
+        object: object.to_string(),
+        ..Default::default()
+    }, &Range::default()).await.with_context(|| format!("Could not download {object} from {bucket}"))?;
+
+    let contents = std::str::from_utf8(file.as_slice()).with_context(|| format!("{object} is not text"))?;
+
+    parse_keys(contents, object)
+}
+
+/// Get the CDSE login from wherever the settings say it is
+pub async fn load(settings: &Settings) -> anyhow::Result<Credentials> {
+    let credentials = &settings.credentials;
+
+    // the settings check makes sure the path is there for the providers that need it
+    let path = || credentials.path.as_deref().unwrap();
+
+    let loaded = match credentials.provider {
+        CredentialProvider::Env => from_env(),
+        CredentialProvider::File => from_file(path()),
+        CredentialProvider::SecretsDir => from_secrets_dir(path()),
+        CredentialProvider::Bucket => from_bucket(settings.bucket.name.as_str(), credentials.object.as_str()).await,
+    }?;
+
+    if loaded.username.is_empty() || loaded.password.is_empty() {
+        return Err(Error::msg(format!("The {:?} credentials have an empty username or password", credentials.provider)));
+    }
+
+    Ok(loaded)
+}
@@ -0,0 +1,167 @@
+use anyhow::Error;
+use opencv::core::{Vector, CV_16U, CV_32F, CV_8U};
+use opencv::imgcodecs::{IMREAD_UNCHANGED, IMWRITE_JPEG_QUALITY, IMWRITE_PNG_COMPRESSION, IMWRITE_WEBP_QUALITY};
+use opencv::prelude::{Mat, MatTraitConst, MatTraitConstManual};
+use rocket::http::ContentType;
+
+/// OpenCV's own jpg quality, which is what everything in the bucket was saved with
+const DEFAULT_JPEG_QUALITY: i32 = 95;
+
+/// What an image is sent back as
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// Quality from 0 to 100
+    Jpeg { quality: i32 },
+    /// Compression level from 0 (none) to 9 (smallest), always lossless
+    Png { compression: i32 },
+    /// Lossless webp
+    WebP,
+    /// 16 bit tiff. Band composites keep the product's digital numbers, anything else is the 8 bit
+    /// render stretched to the full 16 bit range
+    Tiff,
+    /// Numpy array of 32 bit floats, rows by columns (by channels for color images)
+    Npy,
+}
+
+impl Default for Format {
+    fn default() -> Format {
+        Format::Jpeg { quality: DEFAULT_JPEG_QUALITY }
+    }
+}
+
+impl Format {
+    /// Read a format such as "jpeg", "jpeg:80", "png:9", "webp", "tiff" or "npy"
+    pub fn parse(text: &str) -> anyhow::Result<Format> {
+        let mut parts = text.trim().split(':');
+        let name = parts.next().unwrap_or_default().to_lowercase();
+
+        let level = parts.next()
+            .map(|x| x.trim().parse::<i32>().map_err(|_| Error::msg(format!("Bad level in format {text}"))))
+            .transpose()?;
+
+        if parts.next().is_some() {
+            return Err(Error::msg(format!("Too many values in format {text}")));
+        }
+
+        let format = match name.as_str() {
+            "jpg" | "jpeg" => Format::Jpeg { quality: level.unwrap_or(DEFAULT_JPEG_QUALITY) },
+            "png" => Format::Png { compression: level.unwrap_or(3) },
+            "webp" => Format::WebP,
+            "tif" | "tiff" => Format::Tiff,
+            "npy" => Format::Npy,
+            _ => return Err(Error::msg(format!("Unknown format {text}"))),
+        };
+
+        match format {
+            Format::Jpeg { quality } if !(0..=100).contains(&quality) => Err(Error::msg("JPEG quality must be 0 to 100")),
+            Format::Png { compression } if !(0..=9).contains(&compression) => Err(Error::msg("PNG compression must be 0 to 9")),
+            Format::WebP | Format::Tiff | Format::Npy if level.is_some() => Err(Error::msg(format!("{name} takes no level"))),
+            _ => Ok(format),
+        }
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Format::Jpeg { .. } => ContentType::JPEG,
+            Format::Png { .. } => ContentType::PNG,
+            Format::WebP => ContentType::WEBP,
+            Format::Tiff => ContentType::TIFF,
+            Format::Npy => ContentType::Binary,
+        }
+    }
+
+    /// End of the file name in the bucket, so every format and level is cached on its own. The
+    /// default jpg keeps the plain extension
+    pub fn cache_suffix(&self) -> String {
+        match self {
+            Format::Jpeg { quality } if *quality == DEFAULT_JPEG_QUALITY => "jpg".to_string(),
+            Format::Jpeg { quality } => format!("q{quality}.jpg"),
+            Format::Png { compression } => format!("c{compression}.png"),
+            Format::WebP => "webp".to_string(),
+            // tiffs used to be stretched renders, keep those apart
+            Format::Tiff => "u16.tif".to_string(),
+            Format::Npy => "npy".to_string(),
+        }
+    }
+
+    /// Whether the encoded image can be decoded again for resizing
+    pub fn is_image(&self) -> bool {
+        *self != Format::Npy
+    }
+
+    /// Whether enhancements can be applied. Tiffs and arrays hold values for analysis, which a
+    /// contrast stretch would throw away
+    pub fn can_enhance(&self) -> bool {
+        !matches!(self, Format::Tiff | Format::Npy)
+    }
+
+    pub fn encode(&self, image: &Mat) -> Vec<u8> {
+        let (extension, params) = match self {
+            Format::Jpeg { quality } => (".jpg", vec![IMWRITE_JPEG_QUALITY, *quality]),
+            Format::Png { compression } => (".png", vec![IMWRITE_PNG_COMPRESSION, *compression]),
+            // anything over 100 is lossless
+            Format::WebP => (".webp", vec![IMWRITE_WEBP_QUALITY, 101]),
+            Format::Tiff => return encode_tiff(image),
+            Format::Npy => return encode_npy(image),
+        };
+
+        let mut buffer = Vector::new();
+        opencv::imgcodecs::imencode(extension, image, &mut buffer, &Vector::from(params)).unwrap();
+
+        buffer.to_vec()
+    }
+
+    /// Decode an image this format encoded. None for raw arrays
+    pub fn decode(&self, data: &[u8]) -> Option<Mat> {
+        if !self.is_image() {
+            return None;
+        }
+
+        opencv::imgcodecs::imdecode(&Vector::<u8>::from_slice(data), IMREAD_UNCHANGED).ok()
+    }
+}
+
+fn encode_tiff(image: &Mat) -> Vec<u8> {
+    // 255 * 257 is 65535, digital numbers are already 16 bit
+    let scale = if image.depth() == CV_8U { 257.0 } else { 1.0 };
+
+    let mut wide = Mat::default();
+    image.convert_to(&mut wide, CV_16U, scale, 0.0).unwrap();
+
+    let mut buffer = Vector::new();
+    opencv::imgcodecs::imencode(".tif", &wide, &mut buffer, &Default::default()).unwrap();
+
+    buffer.to_vec()
+}
+
+/// Write a version 1.0 .npy file. Color images keep OpenCV's BGR channel order
+fn encode_npy(image: &Mat) -> Vec<u8> {
+    let mut values = Mat::default();
+    image.convert_to(&mut values, CV_32F, 1.0, 0.0).unwrap();
+
+    let shape = if values.channels() == 1 {
+        format!("({}, {})", values.rows(), values.cols())
+    } else {
+        format!("({}, {}, {})", values.rows(), values.cols(), values.channels())
+    };
+
+    let mut header = format!("{{'descr': '<f4', 'fortran_order': False, 'shape': {shape}, }}");
+
+    // magic, version and header length take 10 bytes, and the whole header is padded to 64 bytes
+    // ending in a newline
+    let padding = 64 - (10 + header.len() + 1) % 64;
+    header.push_str(" ".repeat(padding % 64).as_str());
+    header.push('\n');
+
+    let data = values.data_bytes().unwrap();
+
+    let mut to_return = Vec::with_capacity(10 + header.len() + data.len());
+    to_return.extend_from_slice(b"\x93NUMPY\x01\x00");
+    to_return.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    to_return.extend_from_slice(header.as_bytes());
+
+    // OpenCV floats are native endian, which is little endian everywhere we run
+    to_return.extend_from_slice(data);
+
+    to_return
+}
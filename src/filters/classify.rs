@@ -0,0 +1,182 @@
+use opencv::core::{no_array, Size, CMP_EQ, CMP_GT, CMP_LT, CV_8U};
+use opencv::imgproc::INTER_NEAREST;
+use opencv::prelude::{Mat, MatTraitConst, MatTraitConstManual};
+use serde::Serialize;
+
+use crate::filters::index::{valid_mask, Index};
+use crate::sat_data::SatData;
+
+/// Scene classification classes we cannot see the ground through: no data, saturated, cloud
+/// shadow, medium and high probability cloud and thin cirrus
+const SCL_OBSCURED: [f64; 6] = [0.0, 1.0, 3.0, 8.0, 9.0, 10.0];
+
+/// Blue band value (8 bit) above which a level 1C pixel is taken as cloud, roughly a reflectance
+/// of 0.2 once the 1000 offset of newer products is taken off
+const L1C_CLOUD_BLUE: f64 = 12.0;
+
+/// How to pick the index value that splits water from land
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Threshold {
+    /// Anything above this value is water
+    Fixed(f64),
+    /// Pick the value that best separates the two groups in the histogram (Otsu's method)
+    Otsu,
+}
+
+impl Threshold {
+    /// "otsu" or a number
+    pub fn from_name(name: &str) -> Option<Threshold> {
+        if name.eq_ignore_ascii_case("otsu") {
+            Some(Threshold::Otsu)
+        } else {
+            name.parse().ok().map(Threshold::Fixed)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct WaterStats {
+    /// The index value used to split water from land
+    pub threshold: f64,
+    pub water_km2: f64,
+    pub land_km2: f64,
+    /// Area hidden by clouds or without data, which was left out
+    pub obscured_km2: f64,
+}
+
+pub struct WaterClassification {
+    /// 255 for water, 0 for land or obscured pixels
+    pub mask: Mat,
+    pub stats: WaterStats,
+}
+
+/// Otsu's threshold over the valid pixels of an index, returned as an index value
+fn otsu(index: &Mat, valid: &Mat) -> f64 {
+    // map -1..1 to 0..255 so we can use a plain histogram
+    let mut scaled = Mat::default();
+    index.convert_to(&mut scaled, CV_8U, 127.5, 127.5).unwrap();
+
+    let mut hist = [0f64; 256];
+    for (value, is_valid) in scaled.data_bytes().unwrap().iter().zip(valid.data_bytes().unwrap()) {
+        if *is_valid != 0 {
+            hist[*value as usize] += 1.0;
+        }
+    }
+
+    let total: f64 = hist.iter().sum();
+    let sum_all: f64 = hist.iter().enumerate().map(|(i, count)| i as f64 * count).sum();
+
+    let mut best = 127;
+    let mut best_variance = 0.0;
+    let mut weight_low = 0.0;
+    let mut sum_low = 0.0;
+
+    for (i, count) in hist.iter().enumerate() {
+        weight_low += count;
+        sum_low += i as f64 * count;
+
+        let weight_high = total - weight_low;
+        if weight_low == 0.0 || weight_high == 0.0 {
+            continue;
+        }
+
+        let mean_low = sum_low / weight_low;
+        let mean_high = (sum_all - sum_low) / weight_high;
+
+        // between class variance
+        let variance = weight_low * weight_high * (mean_low - mean_high).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best = i;
+        }
+    }
+
+    best as f64 / 127.5 - 1.0
+}
+
+/// Mask (255) of the pixels the scene classification says we can see the ground through
+fn scl_clear(scl: &Mat, size: Size) -> Mat {
+    let mut scl_10m = Mat::default();
+    opencv::imgproc::resize(scl, &mut scl_10m, size, 0.0, 0.0, INTER_NEAREST).unwrap();
+
+    let mut obscured = Mat::default();
+    opencv::core::multiply(&scl_10m, &0.0, &mut obscured, 1.0, -1).unwrap();
+
+    for class in SCL_OBSCURED {
+        let mut is_class = Mat::default();
+        opencv::core::compare(&scl_10m, &class, &mut is_class, CMP_EQ).unwrap();
+
+        let mut next = Mat::default();
+        opencv::core::bitwise_or(&obscured, &is_class, &mut next, &no_array()).unwrap();
+        obscured = next;
+    }
+
+    let mut clear = Mat::default();
+    opencv::core::bitwise_not(&obscured, &mut clear, &no_array()).unwrap();
+
+    clear
+}
+
+/// Mask (255) of the pixels where we can see the ground. Level 2A products use the scene
+/// classification. Level 1C products have none, so bright blue pixels are taken as cloud instead.
+pub fn clear_mask(data: &SatData) -> Mat {
+    let valid = valid_mask(data);
+    let size = valid.size().unwrap();
+
+    let clear = if let Some(scl) = data.get_scl() {
+        scl_clear(&scl, size)
+    } else {
+        let mut not_bright = Mat::default();
+        opencv::core::compare(&data.get_b2(), &L1C_CLOUD_BLUE, &mut not_bright, CMP_LT).unwrap();
+        not_bright
+    };
+
+    let mut to_return = Mat::default();
+    opencv::core::bitwise_and(&valid, &clear, &mut to_return, &no_array()).unwrap();
+
+    to_return
+}
+
+/// Split a product into water and land with NDWI or MNDWI. When use_scl is set and the product has
+/// a scene classification, clouds and their shadows are left out.
+pub fn classify_water(data: &SatData, index: Index, threshold: Threshold, use_scl: bool) -> WaterClassification {
+    let values = index.compute(data);
+    let size = values.size().unwrap();
+
+    let mut valid = valid_mask(data);
+    if let Some(scl) = data.get_scl().filter(|_| use_scl) {
+        let mut clear = Mat::default();
+        opencv::core::bitwise_and(&valid, &scl_clear(&scl, size), &mut clear, &no_array()).unwrap();
+        valid = clear;
+    }
+
+    let threshold = match threshold {
+        Threshold::Fixed(value) => value,
+        Threshold::Otsu => otsu(&values, &valid),
+    };
+
+    let mut above = Mat::default();
+    opencv::core::compare(&values, &threshold, &mut above, CMP_GT).unwrap();
+
+    let mut mask = Mat::default();
+    opencv::core::bitwise_and(&above, &valid, &mut mask, &no_array()).unwrap();
+
+    // default to the 10 m pixel size if the metadata is missing
+    let pixel_area = data.metadata()
+        .map(|m| m.pixel_area_km2(size.width))
+        .unwrap_or(0.0001);
+
+    let total = size.area() as f64;
+    let valid_count = opencv::core::count_non_zero(&valid).unwrap() as f64;
+    let water_count = opencv::core::count_non_zero(&mask).unwrap() as f64;
+
+    WaterClassification {
+        mask,
+        stats: WaterStats {
+            threshold,
+            water_km2: water_count * pixel_area,
+            land_km2: (valid_count - water_count) * pixel_area,
+            obscured_km2: (total - valid_count) * pixel_area,
+        },
+    }
+}
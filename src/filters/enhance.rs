@@ -0,0 +1,291 @@
+use anyhow::Error;
+use opencv::core::{Size, Vector};
+use opencv::imgproc::{COLOR_BGR2HSV, COLOR_BGR2YCrCb, COLOR_HSV2BGR, COLOR_YCrCb2BGR};
+use opencv::prelude::{CLAHETrait, Mat, MatTraitConst, MatTraitConstManual};
+use opencv::types::VectorOfMat;
+
+use crate::encoding::Format;
+use crate::filters::fit;
+
+/// One step of the enhancement pipeline. Steps are applied in the order they are given
+#[derive(Debug, Clone, PartialEq)]
+pub enum Enhancement {
+    /// Stretch the values between the low and high percentile to the full 0 to 255 range. When
+    /// linked, every channel uses the same limits so colors keep their balance
+    Stretch { low: f64, high: f64, linked: bool },
+    /// Gamma correction, values above 1 brighten the dark areas
+    Gamma(f64),
+    /// Histogram equalization. When linked, only the brightness is equalized
+    Equalize { linked: bool },
+    /// Contrast limited adaptive histogram equalization on the brightness
+    Clahe { clip_limit: f64, tile_size: i32 },
+    /// Add a fixed offset to every pixel
+    Brightness(f64),
+    /// Scale the color saturation
+    Saturation(f64),
+    /// Multiply every pixel by a fixed value (the old "contrast" boost)
+    Contrast(f64),
+}
+
+/// Read the argument at index or fall back to the default
+fn arg_or(args: &[&str], index: usize, default: f64) -> anyhow::Result<f64> {
+    match args.get(index) {
+        Some(arg) => arg.parse().map_err(|_| Error::msg(format!("Invalid enhancement value: {arg}"))),
+        None => Ok(default),
+    }
+}
+
+/// Parse a pipeline like "stretch:2:98,gamma:1.2,saturation:1.3". Steps are separated by commas
+/// and their values by colons. Missing values use sensible defaults.
+pub fn parse_enhancements(s: &str) -> anyhow::Result<Vec<Enhancement>> {
+    let mut to_return = Vec::new();
+
+    for step in s.split(',').map(str::trim).filter(|step| !step.is_empty()) {
+        let parts: Vec<&str> = step.split(':').collect();
+        let args = &parts[1..];
+
+        let enhancement = match parts[0].to_lowercase().as_str() {
+            "stretch" => Enhancement::Stretch { low: arg_or(args, 0, 2.0)?, high: arg_or(args, 1, 98.0)?, linked: false },
+            "stretch-linked" => Enhancement::Stretch { low: arg_or(args, 0, 2.0)?, high: arg_or(args, 1, 98.0)?, linked: true },
+            "gamma" => Enhancement::Gamma(arg_or(args, 0, 1.0)?),
+            "equalize" => Enhancement::Equalize { linked: false },
+            "equalize-linked" => Enhancement::Equalize { linked: true },
+            "clahe" => Enhancement::Clahe { clip_limit: arg_or(args, 0, 2.0)?, tile_size: arg_or(args, 1, 8.0)? as i32 },
+            "brightness" => Enhancement::Brightness(arg_or(args, 0, 0.0)?),
+            "saturation" => Enhancement::Saturation(arg_or(args, 0, 1.0)?),
+            "contrast" => Enhancement::Contrast(arg_or(args, 0, 1.0)?),
+            other => return Err(Error::msg(format!("Unknown enhancement: {other}"))),
+        };
+
+        // check the values make sense before we touch any pixels
+        match &enhancement {
+            Enhancement::Stretch { low, high, .. } if *low < 0.0 || *high > 100.0 || low >= high => {
+                return Err(Error::msg("Stretch percentiles must satisfy 0 <= low < high <= 100"));
+            }
+            Enhancement::Gamma(gamma) if *gamma <= 0.0 => return Err(Error::msg("Gamma must be positive")),
+            Enhancement::Clahe { clip_limit, tile_size } if *clip_limit <= 0.0 || *tile_size < 1 => {
+                return Err(Error::msg("CLAHE needs a positive clip limit and tile size"));
+            }
+            _ => {}
+        }
+
+        to_return.push(enhancement);
+    }
+
+    Ok(to_return)
+}
+
+/// Count how often each value shows up. Zero is the no data value of the tiles, so it is skipped
+/// or the black border would drag every stretch down
+fn histogram(channels: &[&Mat]) -> [u64; 256] {
+    let mut hist = [0u64; 256];
+
+    for channel in channels {
+        for value in channel.data_bytes().unwrap() {
+            hist[*value as usize] += 1;
+        }
+    }
+
+    hist[0] = 0;
+
+    hist
+}
+
+/// Find the value below which the given percent of pixels fall
+fn percentile(hist: &[u64; 256], percent: f64) -> f64 {
+    let total: u64 = hist.iter().sum();
+    let target = (total as f64 * percent / 100.0).ceil() as u64;
+
+    let mut seen = 0;
+    for (value, count) in hist.iter().enumerate() {
+        seen += count;
+
+        if seen >= target && seen > 0 {
+            return value as f64;
+        }
+    }
+
+    255.0
+}
+
+/// Map low to 0 and high to 255 with everything in between scaled linearly
+fn stretch_between(m: &Mat, low: f64, high: f64) -> Mat {
+    let alpha = 255.0 / (high - low).max(1.0);
+
+    let mut stretched = Mat::default();
+    m.convert_to(&mut stretched, -1, alpha, -low * alpha).unwrap();
+
+    stretched
+}
+
+fn split(m: &Mat) -> VectorOfMat {
+    let mut channels = VectorOfMat::new();
+    opencv::core::split(m, &mut channels).unwrap();
+
+    channels
+}
+
+fn merge(channels: &VectorOfMat) -> Mat {
+    let mut merged = Mat::default();
+    opencv::core::merge(channels, &mut merged).unwrap();
+
+    merged
+}
+
+/// Run a function over the brightness of an image. Color images go through YCrCb so only the
+/// luma channel is touched
+fn on_luma(m: &Mat, mut f: impl FnMut(&Mat) -> Mat) -> Mat {
+    if m.channels() == 1 {
+        return f(m);
+    }
+
+    let mut ycrcb = Mat::default();
+    opencv::imgproc::cvt_color(m, &mut ycrcb, COLOR_BGR2YCrCb, 0).unwrap();
+
+    let mut channels = split(&ycrcb);
+    channels.set(0, f(&channels.get(0).unwrap())).unwrap();
+
+    let mut to_return = Mat::default();
+    opencv::imgproc::cvt_color(&merge(&channels), &mut to_return, COLOR_YCrCb2BGR, 0).unwrap();
+
+    to_return
+}
+
+fn stretch(m: &Mat, low: f64, high: f64, linked: bool) -> Mat {
+    let channels = split(m);
+
+    if linked || channels.len() == 1 {
+        let all: Vec<Mat> = channels.to_vec();
+        let hist = histogram(&all.iter().collect::<Vec<&Mat>>());
+
+        return stretch_between(m, percentile(&hist, low), percentile(&hist, high));
+    }
+
+    let mut stretched = VectorOfMat::new();
+    for channel in channels.iter() {
+        let hist = histogram(&[&channel]);
+        stretched.push(stretch_between(&channel, percentile(&hist, low), percentile(&hist, high)));
+    }
+
+    merge(&stretched)
+}
+
+fn gamma(m: &Mat, gamma: f64) -> Mat {
+    let table: Vec<u8> = (0..256)
+        .map(|x| (255.0 * (x as f64 / 255.0).powf(1.0 / gamma)).round() as u8)
+        .collect();
+
+    let lut = Mat::from_slice(&table).unwrap();
+
+    let mut to_return = Mat::default();
+    opencv::core::lut(m, &lut, &mut to_return).unwrap();
+
+    to_return
+}
+
+fn equalize(m: &Mat, linked: bool) -> Mat {
+    let equalize_one = |channel: &Mat| {
+        let mut equalized = Mat::default();
+        opencv::imgproc::equalize_hist(channel, &mut equalized).unwrap();
+        equalized
+    };
+
+    if linked {
+        return on_luma(m, equalize_one);
+    }
+
+    let equalized: VectorOfMat = split(m).iter().map(|channel| equalize_one(&channel)).collect();
+
+    merge(&equalized)
+}
+
+fn clahe(m: &Mat, clip_limit: f64, tile_size: i32) -> Mat {
+    let mut clahe = opencv::imgproc::create_clahe(clip_limit, Size::new(tile_size, tile_size)).unwrap();
+
+    on_luma(m, |channel| {
+        let mut to_return = Mat::default();
+        clahe.apply(channel, &mut to_return).unwrap();
+        to_return
+    })
+}
+
+fn saturation(m: &Mat, factor: f64) -> Mat {
+    // nothing to saturate in a gray image
+    if m.channels() != 3 {
+        return m.clone();
+    }
+
+    let mut hsv = Mat::default();
+    opencv::imgproc::cvt_color(m, &mut hsv, COLOR_BGR2HSV, 0).unwrap();
+
+    let mut channels = split(&hsv);
+    let mut saturated = Mat::default();
+    channels.get(1).unwrap().convert_to(&mut saturated, -1, factor, 0.0).unwrap();
+    channels.set(1, saturated).unwrap();
+
+    let mut to_return = Mat::default();
+    opencv::imgproc::cvt_color(&merge(&channels), &mut to_return, COLOR_HSV2BGR, 0).unwrap();
+
+    to_return
+}
+
+/// Run an 8 bit image through every enhancement step in order
+pub fn enhance(image: Mat, steps: &[Enhancement]) -> Mat {
+    let mut image = image;
+
+    for step in steps {
+        image = match *step {
+            Enhancement::Stretch { low, high, linked } => stretch(&image, low, high, linked),
+            Enhancement::Gamma(g) => gamma(&image, g),
+            Enhancement::Equalize { linked } => equalize(&image, linked),
+            Enhancement::Clahe { clip_limit, tile_size } => clahe(&image, clip_limit, tile_size),
+            Enhancement::Brightness(offset) => {
+                let mut m = Mat::default();
+                image.convert_to(&mut m, -1, 1.0, offset).unwrap();
+                m
+            }
+            Enhancement::Saturation(factor) => saturation(&image, factor),
+            Enhancement::Contrast(factor) => {
+                let mut m = Mat::default();
+                image.convert_to(&mut m, -1, factor, 0.0).unwrap();
+                m
+            }
+        };
+    }
+
+    image
+}
+
+/// Encode an enhanced version of a jpg back to jpg
+pub fn enhance_jpg(image: Vec<u8>, steps: &[Enhancement]) -> Vec<u8> {
+    if steps.is_empty() {
+        return image;
+    }
+
+    let conv: Vector<u8> = Vector::from(image);
+    let image_mat = opencv::imgcodecs::imdecode(&conv as _, opencv::imgcodecs::IMREAD_COLOR).unwrap();
+
+    let mut buffer = Vector::new();
+    opencv::imgcodecs::imencode(".jpg", &enhance(image_mat, steps), &mut buffer, &Default::default()).unwrap();
+
+    buffer.to_vec()
+}
+
+/// Shrink an encoded image to fit max_size and enhance it, keeping its format. Raw arrays, and
+/// images with nothing to do, are passed through
+pub fn enhance_encoded(image: Vec<u8>, format: Format, max_size: Option<u32>, steps: &[Enhancement]) -> Vec<u8> {
+    if steps.is_empty() && max_size.is_none() {
+        return image;
+    }
+
+    match format.decode(&image) {
+        Some(mut m) => {
+            if let Some(max_size) = max_size {
+                m = fit(&m, max_size);
+            }
+
+            format.encode(&enhance(m, steps))
+        }
+        None => image,
+    }
+}
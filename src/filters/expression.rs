@@ -0,0 +1,273 @@
+use anyhow::Error;
+use opencv::core::{no_array, Scalar, Size, CV_32F};
+use opencv::prelude::{Mat, MatTraitConst};
+
+use crate::filters::index::to_grid;
+use crate::sat_data::SatData;
+
+/// A parsed band math expression like "(B08 - B04) / (B08 + B04)"
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expression {
+    Number(f64),
+    /// Band number, 1 to 12
+    Band(usize),
+    Negate(Box<Expression>),
+    Add(Box<Expression>, Box<Expression>),
+    Subtract(Box<Expression>, Box<Expression>),
+    Multiply(Box<Expression>, Box<Expression>),
+    Divide(Box<Expression>, Box<Expression>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Band(usize),
+    Operator(char),
+}
+
+fn tokenize(s: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = s.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+
+        if c.is_whitespace() {
+            i += 1;
+        } else if "+-*/()".contains(c) {
+            tokens.push(Token::Operator(c));
+            i += 1;
+        } else if c == 'B' || c == 'b' {
+            let start = i + 1;
+            i = start;
+            while i < chars.len() && chars[i].is_ascii_digit() {
+                i += 1;
+            }
+
+            let band: usize = chars[start..i].iter().collect::<String>().parse()
+                .map_err(|_| Error::msg("Expected a band number after B"))?;
+
+            if !(1..=12).contains(&band) {
+                return Err(Error::msg(format!("There is no band B{band}")));
+            }
+
+            tokens.push(Token::Band(band));
+        } else if c.is_ascii_digit() || c == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+
+            let number = chars[start..i].iter().collect::<String>();
+            tokens.push(Token::Number(number.parse().map_err(|_| Error::msg(format!("Invalid number {number}")))?));
+        } else {
+            return Err(Error::msg(format!("Unexpected character {c}")));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Recursive descent parser, lowest precedence first
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn advance(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    // sum = product (("+" | "-") product)*
+    fn sum(&mut self) -> anyhow::Result<Expression> {
+        let mut left = self.product()?;
+
+        while let Some(Token::Operator(op)) = self.peek().cloned() {
+            if op != '+' && op != '-' {
+                break;
+            }
+            self.advance();
+
+            let right = self.product()?;
+            left = if op == '+' {
+                Expression::Add(Box::new(left), Box::new(right))
+            } else {
+                Expression::Subtract(Box::new(left), Box::new(right))
+            };
+        }
+
+        Ok(left)
+    }
+
+    // product = unary (("*" | "/") unary)*
+    fn product(&mut self) -> anyhow::Result<Expression> {
+        let mut left = self.unary()?;
+
+        while let Some(Token::Operator(op)) = self.peek().cloned() {
+            if op != '*' && op != '/' {
+                break;
+            }
+            self.advance();
+
+            let right = self.unary()?;
+            left = if op == '*' {
+                Expression::Multiply(Box::new(left), Box::new(right))
+            } else {
+                Expression::Divide(Box::new(left), Box::new(right))
+            };
+        }
+
+        Ok(left)
+    }
+
+    // unary = "-" unary | atom
+    fn unary(&mut self) -> anyhow::Result<Expression> {
+        if self.peek() == Some(&Token::Operator('-')) {
+            self.advance();
+            return Ok(Expression::Negate(Box::new(self.unary()?)));
+        }
+
+        self.atom()
+    }
+
+    // atom = number | band | "(" sum ")"
+    fn atom(&mut self) -> anyhow::Result<Expression> {
+        match self.advance() {
+            Some(Token::Number(n)) => Ok(Expression::Number(n)),
+            Some(Token::Band(b)) => Ok(Expression::Band(b)),
+            Some(Token::Operator('(')) => {
+                let inner = self.sum()?;
+
+                if self.advance() != Some(Token::Operator(')')) {
+                    return Err(Error::msg("Missing closing bracket"));
+                }
+
+                Ok(inner)
+            }
+            Some(token) => Err(Error::msg(format!("Unexpected {token:?}"))),
+            None => Err(Error::msg("Expression ended early")),
+        }
+    }
+}
+
+/// Either a plain number or a full image, so constants never need an image of their own
+enum Value {
+    Scalar(f64),
+    Image(Mat),
+}
+
+fn scale(m: &Mat, alpha: f64, beta: f64) -> Mat {
+    let mut to_return = Mat::default();
+    m.convert_to(&mut to_return, CV_32F, alpha, beta).unwrap();
+
+    to_return
+}
+
+impl Expression {
+    pub fn parse(s: &str) -> anyhow::Result<Expression> {
+        let mut parser = Parser { tokens: tokenize(s)?, position: 0 };
+        let expression = parser.sum()?;
+
+        if parser.position < parser.tokens.len() {
+            return Err(Error::msg("Unexpected text after the expression"));
+        }
+
+        Ok(expression)
+    }
+
+    fn evaluate_value(&self, data: &SatData, size: Size) -> Value {
+        match self {
+            Expression::Number(n) => Value::Scalar(*n),
+            Expression::Band(b) => Value::Image(scale(&to_grid(data.get_band(*b).unwrap(), size), 1.0, 0.0)),
+            Expression::Negate(inner) => match inner.evaluate_value(data, size) {
+                Value::Scalar(n) => Value::Scalar(-n),
+                Value::Image(m) => Value::Image(scale(&m, -1.0, 0.0)),
+            },
+            Expression::Add(a, b) => match (a.evaluate_value(data, size), b.evaluate_value(data, size)) {
+                (Value::Scalar(x), Value::Scalar(y)) => Value::Scalar(x + y),
+                (Value::Image(m), Value::Scalar(n)) | (Value::Scalar(n), Value::Image(m)) => Value::Image(scale(&m, 1.0, n)),
+                (Value::Image(x), Value::Image(y)) => {
+                    let mut m = Mat::default();
+                    opencv::core::add(&x, &y, &mut m, &no_array(), -1).unwrap();
+                    Value::Image(m)
+                }
+            },
+            Expression::Subtract(a, b) => match (a.evaluate_value(data, size), b.evaluate_value(data, size)) {
+                (Value::Scalar(x), Value::Scalar(y)) => Value::Scalar(x - y),
+                (Value::Image(m), Value::Scalar(n)) => Value::Image(scale(&m, 1.0, -n)),
+                (Value::Scalar(n), Value::Image(m)) => Value::Image(scale(&m, -1.0, n)),
+                (Value::Image(x), Value::Image(y)) => {
+                    let mut m = Mat::default();
+                    opencv::core::subtract(&x, &y, &mut m, &no_array(), -1).unwrap();
+                    Value::Image(m)
+                }
+            },
+            Expression::Multiply(a, b) => match (a.evaluate_value(data, size), b.evaluate_value(data, size)) {
+                (Value::Scalar(x), Value::Scalar(y)) => Value::Scalar(x * y),
+                (Value::Image(m), Value::Scalar(n)) | (Value::Scalar(n), Value::Image(m)) => Value::Image(scale(&m, n, 0.0)),
+                (Value::Image(x), Value::Image(y)) => {
+                    let mut m = Mat::default();
+                    opencv::core::multiply(&x, &y, &mut m, 1.0, -1).unwrap();
+                    Value::Image(m)
+                }
+            },
+            Expression::Divide(a, b) => match (a.evaluate_value(data, size), b.evaluate_value(data, size)) {
+                (Value::Scalar(x), Value::Scalar(y)) => Value::Scalar(x / y),
+                (Value::Image(m), Value::Scalar(n)) => Value::Image(scale(&m, 1.0 / n, 0.0)),
+                (Value::Scalar(n), Value::Image(m)) => {
+                    let mut to_return = Mat::default();
+                    opencv::core::divide(n, &m, &mut to_return, -1).unwrap();
+                    Value::Image(to_return)
+                }
+                (Value::Image(x), Value::Image(y)) => {
+                    let mut m = Mat::default();
+                    opencv::core::divide2(&x, &y, &mut m, 1.0, -1).unwrap();
+                    Value::Image(m)
+                }
+            },
+        }
+    }
+
+    /// Every band the expression reads
+    fn bands(&self) -> Vec<usize> {
+        match self {
+            Expression::Number(_) => Vec::new(),
+            Expression::Band(b) => vec![*b],
+            Expression::Negate(inner) => inner.bands(),
+            Expression::Add(a, b) | Expression::Subtract(a, b) | Expression::Multiply(a, b) | Expression::Divide(a, b) => {
+                let mut bands = a.bands();
+                bands.extend(b.bands());
+                bands
+            }
+        }
+    }
+
+    /// Run the expression over a product on the 10 m grid as 32 bit floats. 0 / 0 comes out as zero
+    pub fn evaluate(&self, data: &SatData) -> anyhow::Result<Mat> {
+        // level 2A products have no B10
+        for band in self.bands() {
+            if data.get_band(band).map_or(true, |m| m.empty()) {
+                return Err(Error::msg(format!("Product has no band B{band}")));
+            }
+        }
+
+        let size = data.get_b4().size().unwrap();
+
+        let mut to_return = match self.evaluate_value(data, size) {
+            Value::Image(m) => m,
+            Value::Scalar(n) => Mat::new_size_with_default(size, CV_32F, Scalar::all(n)).unwrap(),
+        };
+
+        opencv::core::patch_na_ns(&mut to_return, 0.0).unwrap();
+
+        Ok(to_return)
+    }
+}
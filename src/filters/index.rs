@@ -0,0 +1,97 @@
+use opencv::core::{no_array, Size, CMP_GT, CV_32F};
+use opencv::prelude::{Mat, MatTraitConst};
+
+use crate::filters::sharpen::upscale;
+use crate::sat_data::SatData;
+
+/// Normalized difference indexes, all between -1 and 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Index {
+    /// Vegetation, (B8 - B4) / (B8 + B4)
+    Ndvi,
+    /// Open water, (B3 - B8) / (B3 + B8)
+    Ndwi,
+    /// Modified water index, better at telling water from built up areas, (B3 - B11) / (B3 + B11)
+    Mndwi,
+    /// Normalized burn ratio, drops sharply after a fire, (B8 - B12) / (B8 + B12)
+    Nbr,
+    /// Moisture in plants, (B8 - B11) / (B8 + B11)
+    Ndmi,
+}
+
+impl Index {
+    pub fn from_name(name: &str) -> Option<Index> {
+        match name.to_uppercase().as_str() {
+            "NDVI" => Some(Index::Ndvi),
+            "NDWI" => Some(Index::Ndwi),
+            "MNDWI" => Some(Index::Mndwi),
+            "NBR" => Some(Index::Nbr),
+            "NDMI" => Some(Index::Ndmi),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Index::Ndvi => "NDVI",
+            Index::Ndwi => "NDWI",
+            Index::Mndwi => "MNDWI",
+            Index::Nbr => "NBR",
+            Index::Ndmi => "NDMI",
+        }
+    }
+
+    /// Compute the index on the 10 m grid as 32 bit floats. 20 m bands are scaled up to match.
+    pub fn compute(&self, data: &SatData) -> Mat {
+        let (a, b) = match self {
+            Index::Ndvi => (data.get_b8(), data.get_b4()),
+            Index::Ndwi => (data.get_b3(), data.get_b8()),
+            Index::Mndwi => (data.get_b3(), data.get_b11()),
+            Index::Nbr => (data.get_b8(), data.get_b12()),
+            Index::Ndmi => (data.get_b8(), data.get_b11()),
+        };
+
+        let size = data.get_b4().size().unwrap();
+
+        normalized_difference(&to_grid(a, size), &to_grid(b, size))
+    }
+}
+
+/// Bring a band up to the given size if it is not already
+pub fn to_grid(m: Mat, size: Size) -> Mat {
+    if m.size().unwrap() == size {
+        m
+    } else {
+        upscale(&m, size)
+    }
+}
+
+/// (a - b) / (a + b) as 32 bit floats. Pixels where both bands are zero come out as zero
+pub fn normalized_difference(a: &Mat, b: &Mat) -> Mat {
+    let mut a_f32 = Mat::default();
+    let mut b_f32 = Mat::default();
+    a.convert_to(&mut a_f32, CV_32F, 1.0, 0.0).unwrap();
+    b.convert_to(&mut b_f32, CV_32F, 1.0, 0.0).unwrap();
+
+    let mut top = Mat::default();
+    let mut bottom = Mat::default();
+    let mut to_return = Mat::default();
+
+    opencv::core::subtract(&a_f32, &b_f32, &mut top, &no_array(), -1).unwrap();
+    opencv::core::add(&a_f32, &b_f32, &mut bottom, &no_array(), -1).unwrap();
+    opencv::core::divide2(&top, &bottom, &mut to_return, 1.0, -1).unwrap();
+
+    // 0 / 0 gives NaN
+    opencv::core::patch_na_ns(&mut to_return, 0.0).unwrap();
+
+    to_return
+}
+
+/// Mask (255 for valid) of pixels that were actually imaged. Outside the satellite's swath the
+/// bands are zero.
+pub fn valid_mask(data: &SatData) -> Mat {
+    let mut to_return = Mat::default();
+    opencv::core::compare(&data.get_b2(), &0.0, &mut to_return, CMP_GT).unwrap();
+
+    to_return
+}
@@ -2,26 +2,20 @@ use opencv::core::{no_array, Vector};
 use opencv::prelude::{Mat, MatTrait, MatTraitConst};
 use opencv::types::VectorOfMat;
 
+use crate::filters::classify::{classify_water, Threshold};
+use crate::filters::index::Index;
+use crate::filters::sharpen::{average, sharpen, SharpenMethod};
 use crate::sat_data::SatData;
 
-//. This will reduce the number pixels in an image by some ratio
-fn down_sample_u8(m: Mat, ratio: usize) -> Mat {
-    let mut new_m = unsafe {
-        Mat::new_rows_cols(m.size().unwrap().width / ratio as i32, m.size().unwrap().height / ratio as i32, m.typ()).unwrap()
-    };
-
-    // mark areas less then 0.5 as water and above as land
-    for x in (0..m.size().unwrap().width).step_by(ratio) {
-        for y in (0..m.size().unwrap().height).step_by(ratio) {
-            *new_m.at_2d_mut::<u8>(x / ratio as i32, y / ratio as i32).unwrap() = *m.at_2d::<u8>(x, y).unwrap();
-        }
-    }
-
-    new_m
-}
+pub mod classify;
+pub mod enhance;
+pub mod expression;
+pub mod index;
+pub mod sar;
+pub mod sharpen;
 
 /// Basic combination of colors in red, green, and blue for the respective bands
-fn simple_composite(r: Mat, g: Mat, b: Mat) -> Mat {
+pub(crate) fn simple_composite(r: Mat, g: Mat, b: Mat) -> Mat {
     println!("r depth: {:?}", r.depth());
     println!("g depth: {:?}", g.depth());
     println!("b depth: {:?}", b.depth());
@@ -73,7 +67,8 @@ pub fn ndwi(data: &SatData) -> Mat {
     // finish ndwi
     opencv::core::divide2(&top, &bottom, &mut ndwi_water, 1.0, -1).unwrap();
 
-    // mark areas less then 0.5 as water and above as land
+    // land brightness falls off with the cube of the distance from pure water. this is only for
+    // looks, see classify::classify_water for an actual water mask
     for x in 0..nir.size().unwrap().width {
         for y in 0..nir.size().unwrap().height {
             *ndwi_land.at_2d_mut::<f32>(x, y).unwrap() = (1.0 - ndwi_water.at_2d::<f32>(x, y).unwrap()).powi(3);
@@ -109,7 +104,8 @@ pub fn false_color(data: &SatData) -> Mat {
 
 /// This highlights the density of water in green. The more green an area is, the more moister they
 /// have. This will highlight dense vegetation areas and the presence of ice or rain in clouds. This
-/// will also can highlight areas lacking in water like burn areas.
+/// will also can highlight areas lacking in water like burn areas. B12 is only 20 m, so it gets
+/// sharpened with the near infrared band to keep the whole image at 10 m.
 pub fn swir(data: &SatData) -> Mat {
 
     // get bands
@@ -117,12 +113,125 @@ pub fn swir(data: &SatData) -> Mat {
     let b8 = data.get_b8();
     let b12 = data.get_b12();
 
-    let b4_down_sampled = down_sample_u8(b4, 2);
-    let b8_down_sampled = down_sample_u8(b8, 2);
+    let b12_sharpened = sharpen(&[b12], &b8, SharpenMethod::HighPass).remove(0);
 
     simple_composite(
-        b4_down_sampled,
-        b8_down_sampled,
-        b12,
+        b4,
+        b8,
+        b12_sharpened,
     )
 }
+
+/// The three red edge bands as red, green and blue. These sit between red and near infrared, so
+/// they are very sensitive to the health of plants. They are 20 m bands, so they are sharpened
+/// with the average of red and near infrared to bring them to 10 m.
+pub fn red_edge(data: &SatData, method: SharpenMethod) -> Mat {
+    let pan = average(&[data.get_b4(), data.get_b8()]);
+
+    let mut sharpened = sharpen(&[data.get_b7(), data.get_b6(), data.get_b5()], &pan, method);
+
+    let b5 = sharpened.pop().unwrap();
+    let b6 = sharpened.pop().unwrap();
+    let b7 = sharpened.pop().unwrap();
+
+    simple_composite(b7, b6, b5)
+}
+
+/// Run the filter with the given name. Anything unknown defaults to true color
+pub fn apply_filter(filter: &str, data: &SatData) -> Mat {
+    // radar products have none of the optical bands, they get their own filters
+    if let Some(bands) = data.sar() {
+        return sar::render(filter, bands, data.scale());
+    }
+
+    match filter {
+        "False Color" => false_color(data),
+        "NDWI" => ndwi(data),
+        "SWIR" => swir(data),
+        "Water Mask" => classify_water(data, Index::Ndwi, Threshold::Otsu, true).mask,
+        // "Red Edge" on its own uses high pass, "Red Edge Brovey" or "Red Edge IHS" pick the method
+        name if name.starts_with("Red Edge") => {
+            let method = SharpenMethod::from_name(name["Red Edge".len()..].trim());
+            red_edge(data, method.unwrap_or(SharpenMethod::HighPass))
+        }
+        _ => true_color(data),
+    }
+}
+
+/// Bands of the filters that only put three bands side by side as red, green and blue, with the
+/// sharpening left out. None for radar products and filters that compute something
+fn composite_bands(filter: &str, data: &SatData) -> Option<[usize; 3]> {
+    if data.sar().is_some() {
+        return None;
+    }
+
+    match filter {
+        "NDWI" | "Water Mask" => None,
+        "False Color" => Some([8, 3, 2]),
+        "SWIR" => Some([4, 8, 12]),
+        name if name.starts_with("Red Edge") => Some([7, 6, 5]),
+        _ => Some([4, 3, 2]),
+    }
+}
+
+/// The filter's bands as 16 bit digital numbers, for filters that are a plain composite. 20 m and
+/// 60 m bands are brought to the size of the largest one by repeating pixels, so every value is
+/// one the product has. None when the filter computes something or a band is missing
+pub fn raw_composite(filter: &str, data: &SatData) -> Option<Mat> {
+    let bands = composite_bands(filter, data)?
+        .iter()
+        .map(|band| data.get_raw_band(*band).filter(|m| !m.empty()))
+        .collect::<Option<Vec<Mat>>>()?;
+
+    let size = bands.iter().map(|m| m.size().unwrap()).max_by_key(|size| size.width)?;
+
+    let mut resized = bands.into_iter().map(|m| {
+        if m.size().unwrap() == size {
+            return m;
+        }
+
+        let mut to_return = Mat::default();
+        opencv::imgproc::resize(&m, &mut to_return, size, 0.0, 0.0, opencv::imgproc::INTER_NEAREST).unwrap();
+
+        to_return
+    });
+
+    let (r, g, b) = (resized.next()?, resized.next()?, resized.next()?);
+
+    Some(simple_composite(r, g, b))
+}
+
+/// Bumped when something every filter goes through changes, like how bands are loaded or composited
+const COMMON_VERSION: u32 = 2;
+
+/// Filters that have drawn something different since versioning started, with their current
+/// version. Everything else is at 1
+const FILTER_VERSIONS: &[(&str, u32)] = &[];
+
+/// Version of what a filter draws. It is part of the name renders and tiles are cached under, so
+/// add the filter to FILTER_VERSIONS (or bump its number there) whenever a change makes it draw
+/// something different (other bands, new contrast, a fixed bug) and the stale renders stop being
+/// served
+pub fn filter_version(filter: &str) -> String {
+    let own = FILTER_VERSIONS.iter()
+        .find(|(name, _)| *name == filter)
+        .map_or(1, |(_, version)| *version);
+
+    format!("{COMMON_VERSION}.{own}")
+}
+
+/// Shrink an image so neither side is over max_size, keeping its shape. Smaller images are left
+/// alone
+pub fn fit(image: &Mat, max_size: u32) -> Mat {
+    let longest = image.cols().max(image.rows()) as f64;
+    if longest <= max_size as f64 {
+        return image.clone();
+    }
+
+    let factor = max_size as f64 / longest;
+
+    let mut to_return = Mat::default();
+    opencv::imgproc::resize(image, &mut to_return, opencv::core::Size::default(), factor, factor, opencv::imgproc::INTER_AREA).unwrap();
+
+    to_return
+}
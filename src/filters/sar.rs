@@ -0,0 +1,170 @@
+use opencv::core::{Point, Size, BORDER_REFLECT, CV_8U};
+use opencv::prelude::{Mat, MatTraitConst, MatTraitConstManual, MatTraitManual};
+
+use crate::filters::simple_composite;
+use crate::sar::{Polarisation, SarBands};
+
+/// Equivalent number of looks of a full resolution IW GRD product, the speckle averaging already
+/// done by ESA. Every overview averages scale² more pixels on top
+const GRD_LOOKS: f64 = 4.4;
+
+/// Side of the Lee filter window in pixels
+const LEE_WINDOW: i32 = 5;
+
+/// Backscatter in dB stretched over the whole 0 to 255 range, so typical land and water fill it
+const VV_RANGE: (f64, f64) = (-25.0, 0.0);
+const VH_RANGE: (f64, f64) = (-30.0, -5.0);
+const RATIO_RANGE: (f64, f64) = (0.0, 15.0);
+
+/// What can be drawn from a radar product
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SarFilter {
+    Vv,
+    Vh,
+    /// VV over VH, as the difference of the two in dB
+    Ratio,
+    /// VV, VH and their ratio as red, green and blue
+    Composite,
+}
+
+impl SarFilter {
+    /// "VV", "VH", "VV-VH" or "SAR RGB"
+    pub fn from_name(name: &str) -> Option<SarFilter> {
+        match name.to_uppercase().as_str() {
+            "VV" => Some(SarFilter::Vv),
+            "VH" => Some(SarFilter::Vh),
+            "VV-VH" => Some(SarFilter::Ratio),
+            "SAR RGB" => Some(SarFilter::Composite),
+            _ => None,
+        }
+    }
+}
+
+/// Lee speckle filter. Flat areas are smoothed towards their local mean, while edges and bright
+/// targets, where the local variance is well above what speckle alone gives for this many looks,
+/// are kept. Full size radar bands are large, so apart from the two window means everything is
+/// worked out pixel by pixel and written over the mean of squares
+pub fn lee(m: &Mat, window: i32, looks: f64) -> Mat {
+    let size = Size::new(window, window);
+
+    let mut mean = Mat::default();
+    opencv::imgproc::box_filter(m, &mut mean, -1, size, Point::new(-1, -1), true, BORDER_REFLECT).unwrap();
+
+    let mut to_return = Mat::default();
+    opencv::imgproc::sqr_box_filter(m, &mut to_return, -1, size, Point::new(-1, -1), true, BORDER_REFLECT).unwrap();
+
+    for row in 0..m.rows() {
+        let values = m.at_row::<f32>(row).unwrap();
+        let means = mean.at_row::<f32>(row).unwrap();
+
+        for ((out, value), mean) in to_return.at_row_mut::<f32>(row).unwrap().iter_mut().zip(values).zip(means) {
+            let (value, mean, mean_square) = (*value as f64, *mean as f64, *out as f64);
+
+            let variance = mean_square - mean * mean;
+
+            // speckle on its own has a variance of mean² / looks. The weight is how much of the
+            // local variation is real, from 0 (all speckle) to 1
+            let weight = ((variance - mean * mean / looks) / variance).clamp(0.0, 1.0);
+            let weight = if weight.is_nan() { 0.0 } else { weight };
+
+            *out = (mean + weight * (value - mean)) as f32;
+        }
+    }
+
+    to_return
+}
+
+/// Linear backscatter to dB, in place. Zero (outside the swath) comes out as -60 dB
+pub fn to_db(m: &mut Mat) {
+    for row in 0..m.rows() {
+        for value in m.at_row_mut::<f32>(row).unwrap() {
+            *value = 10.0 * value.max(1e-6).log10();
+        }
+    }
+}
+
+/// a - b in place, which for dB is the ratio of the linear values
+fn subtract(a: &mut Mat, b: &Mat) {
+    for row in 0..a.rows() {
+        for (x, y) in a.at_row_mut::<f32>(row).unwrap().iter_mut().zip(b.at_row::<f32>(row).unwrap()) {
+            *x -= *y;
+        }
+    }
+}
+
+/// Stretch dB values between low and high over 0 to 255, clipping anything outside
+fn stretch(db: &Mat, (low, high): (f64, f64)) -> Mat {
+    let alpha = 255.0 / (high - low);
+
+    let mut to_return = Mat::default();
+    db.convert_to(&mut to_return, CV_8U, alpha, -low * alpha).unwrap();
+
+    to_return
+}
+
+/// Speckle filtered backscatter of one polarisation in dB
+fn backscatter(bands: &SarBands, polarisation: Polarisation, scale: u32) -> Option<Mat> {
+    let looks = GRD_LOOKS * (scale * scale) as f64;
+
+    bands.get(polarisation).map(|m| {
+        let mut filtered = lee(m, LEE_WINDOW, looks);
+        to_db(&mut filtered);
+
+        filtered
+    })
+}
+
+/// VV minus VH in dB
+fn ratio(bands: &SarBands, scale: u32) -> Option<Mat> {
+    let mut vv = backscatter(bands, Polarisation::Vv, scale)?;
+    subtract(&mut vv, &backscatter(bands, Polarisation::Vh, scale)?);
+
+    Some(vv)
+}
+
+/// The values behind a filter as 32 bit floats: dB for a polarisation, and VV minus VH in dB for
+/// the ratio. None for the composite or when the product lacks a band
+pub fn values(filter: SarFilter, bands: &SarBands, scale: u32) -> Option<Mat> {
+    match filter {
+        SarFilter::Vv => backscatter(bands, Polarisation::Vv, scale),
+        SarFilter::Vh => backscatter(bands, Polarisation::Vh, scale),
+        SarFilter::Ratio => ratio(bands, scale),
+        SarFilter::Composite => None,
+    }
+}
+
+/// Draw a filter. Anything that is not a radar filter gets the composite, and anything the product
+/// has no bands for (VH of a single polarisation product) gets whichever band it has in gray. Only
+/// the bands the filter needs are filtered, one full size float band at a time where possible
+pub fn render(filter: &str, bands: &SarBands, scale: u32) -> Mat {
+    let filter = SarFilter::from_name(filter).unwrap_or(SarFilter::Composite);
+
+    let has_vv = bands.get(Polarisation::Vv).is_some();
+    let has_vh = bands.get(Polarisation::Vh).is_some();
+
+    // SarBands::load makes sure there is at least one band
+    let filter = match filter {
+        SarFilter::Composite | SarFilter::Ratio if has_vv && has_vh => filter,
+        SarFilter::Vh if has_vh => SarFilter::Vh,
+        _ if has_vv => SarFilter::Vv,
+        _ => SarFilter::Vh,
+    };
+
+    match filter {
+        SarFilter::Vv => stretch(&backscatter(bands, Polarisation::Vv, scale).unwrap(), VV_RANGE),
+        SarFilter::Vh => stretch(&backscatter(bands, Polarisation::Vh, scale).unwrap(), VH_RANGE),
+        SarFilter::Ratio => stretch(&ratio(bands, scale).unwrap(), RATIO_RANGE),
+        SarFilter::Composite => {
+            let mut vv = backscatter(bands, Polarisation::Vv, scale).unwrap();
+            let red = stretch(&vv, VV_RANGE);
+
+            let vh = backscatter(bands, Polarisation::Vh, scale).unwrap();
+            let green = stretch(&vh, VH_RANGE);
+
+            subtract(&mut vv, &vh);
+            drop(vh);
+
+            simple_composite(red, green, stretch(&vv, RATIO_RANGE))
+        }
+    }
+}
@@ -0,0 +1,145 @@
+use opencv::core::{no_array, Point, Size, BORDER_DEFAULT, CV_32F, CV_8U};
+use opencv::imgproc::INTER_CUBIC;
+use opencv::prelude::{Mat, MatTraitConst};
+
+/// The ways we can use a sharp 10 m band to sharpen blurry 20 m bands
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SharpenMethod {
+    /// Scale every band by the ratio between the sharp band and the average of the blurry ones
+    Brovey,
+    /// Swap the intensity (average of the bands) with the sharp band, keeping the color
+    Ihs,
+    /// Add the fine detail (high pass) of the sharp band on top of every blurry band
+    HighPass,
+}
+
+impl SharpenMethod {
+    pub fn from_name(name: &str) -> Option<SharpenMethod> {
+        match name.to_lowercase().as_str() {
+            "brovey" => Some(SharpenMethod::Brovey),
+            "ihs" => Some(SharpenMethod::Ihs),
+            "hpf" | "high pass" => Some(SharpenMethod::HighPass),
+            _ => None,
+        }
+    }
+}
+
+fn to_f32(m: &Mat) -> Mat {
+    let mut to_return = Mat::default();
+    m.convert_to(&mut to_return, CV_32F, 1.0, 0.0).unwrap();
+
+    to_return
+}
+
+fn to_u8(m: &Mat) -> Mat {
+    let mut to_return = Mat::default();
+    m.convert_to(&mut to_return, CV_8U, 1.0, 0.0).unwrap();
+
+    to_return
+}
+
+/// Scale a band up to the given size with cubic interpolation
+pub fn upscale(m: &Mat, size: Size) -> Mat {
+    let mut to_return = Mat::default();
+    opencv::imgproc::resize(m, &mut to_return, size, 0.0, 0.0, INTER_CUBIC).unwrap();
+
+    to_return
+}
+
+fn mean_std(m: &Mat) -> (f64, f64) {
+    let mut mean = Mat::default();
+    let mut std = Mat::default();
+    opencv::core::mean_std_dev(m, &mut mean, &mut std, &no_array()).unwrap();
+
+    (*mean.at::<f64>(0).unwrap(), *std.at::<f64>(0).unwrap())
+}
+
+/// Average of a list of bands of the same size, in 32 bit float
+pub fn average(bands: &[Mat]) -> Mat {
+    let mut sum = to_f32(&bands[0]);
+
+    for band in &bands[1..] {
+        let mut next = Mat::default();
+        opencv::core::add(&sum, &to_f32(band), &mut next, &no_array(), -1).unwrap();
+        sum = next;
+    }
+
+    let mut to_return = Mat::default();
+    sum.convert_to(&mut to_return, CV_32F, 1.0 / bands.len() as f64, 0.0).unwrap();
+
+    to_return
+}
+
+/// Sharpen low resolution bands with a high resolution "pan" band. The bands are brought up to the
+/// size of the pan band and returned as 8 bit images. Brovey and IHS work on the bands together,
+/// so they need more than one band to be useful; high pass works on each band on its own.
+pub fn sharpen(bands: &[Mat], pan: &Mat, method: SharpenMethod) -> Vec<Mat> {
+    let size = pan.size().unwrap();
+    let pan_f32 = to_f32(pan);
+
+    let upscaled: Vec<Mat> = bands.iter().map(|band| to_f32(&upscale(band, size))).collect();
+
+    let sharpened: Vec<Mat> = match method {
+        SharpenMethod::Brovey => {
+            // add one so the no data areas do not divide by zero
+            let mut intensity = Mat::default();
+            average(&upscaled).convert_to(&mut intensity, CV_32F, 1.0, 1.0).unwrap();
+
+            let mut ratio = Mat::default();
+            opencv::core::divide2(&pan_f32, &intensity, &mut ratio, 1.0, -1).unwrap();
+
+            upscaled.iter().map(|band| {
+                let mut m = Mat::default();
+                opencv::core::multiply(band, &ratio, &mut m, 1.0, -1).unwrap();
+                m
+            }).collect()
+        }
+        SharpenMethod::Ihs => {
+            let intensity = average(&upscaled);
+
+            // match the pan band to the intensity so the brightness does not shift
+            let (pan_mean, pan_std) = mean_std(&pan_f32);
+            let (i_mean, i_std) = mean_std(&intensity);
+            let gain = i_std / pan_std.max(f64::EPSILON);
+
+            let mut matched = Mat::default();
+            pan_f32.convert_to(&mut matched, CV_32F, gain, i_mean - pan_mean * gain).unwrap();
+
+            let mut detail = Mat::default();
+            opencv::core::subtract(&matched, &intensity, &mut detail, &no_array(), -1).unwrap();
+
+            upscaled.iter().map(|band| {
+                let mut m = Mat::default();
+                opencv::core::add(band, &detail, &mut m, &no_array(), -1).unwrap();
+                m
+            }).collect()
+        }
+        SharpenMethod::HighPass => {
+            // the low pass window covers one low resolution pixel
+            let ratio = (size.width / bands[0].size().unwrap().width).max(1);
+            let window = ratio * 2 + 1;
+
+            let mut low_pass = Mat::default();
+            opencv::imgproc::blur(&pan_f32, &mut low_pass, Size::new(window, window), Point::new(-1, -1), BORDER_DEFAULT).unwrap();
+
+            let mut detail = Mat::default();
+            opencv::core::subtract(&pan_f32, &low_pass, &mut detail, &no_array(), -1).unwrap();
+
+            let (_, pan_std) = mean_std(&pan_f32);
+
+            upscaled.iter().map(|band| {
+                // scale the detail to the band so dark bands do not get overwhelmed
+                let (_, band_std) = mean_std(band);
+
+                let mut scaled_detail = Mat::default();
+                detail.convert_to(&mut scaled_detail, CV_32F, band_std / pan_std.max(f64::EPSILON), 0.0).unwrap();
+
+                let mut m = Mat::default();
+                opencv::core::add(band, &scaled_detail, &mut m, &no_array(), -1).unwrap();
+                m
+            }).collect()
+        }
+    };
+
+    sharpened.iter().map(to_u8).collect()
+}
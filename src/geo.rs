@@ -0,0 +1,173 @@
+use std::f64::consts::PI;
+
+// WGS84 ellipsoid and the UTM scale on the central meridian
+const A: f64 = 6378137.0;
+const F: f64 = 1.0 / 298.257223563;
+const K0: f64 = 0.9996;
+const FALSE_EASTING: f64 = 500000.0;
+const FALSE_NORTHING_SOUTH: f64 = 10000000.0;
+
+/// A polygon from a GeoJSON file, in longitude and latitude. The first ring is the outside, any
+/// others are holes. Points are kept as a single ring with one corner
+#[derive(Debug, Clone)]
+pub struct Polygon {
+    pub properties: serde_json::Value,
+    pub rings: Vec<Vec<(f64, f64)>>,
+}
+
+/// A UTM zone, as used by the Sentinel 2 tiles (EPSG 326xx for north, 327xx for south)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UtmZone {
+    pub zone: u32,
+    pub north: bool,
+}
+
+impl Polygon {
+    pub fn is_point(&self) -> bool {
+        self.rings.len() == 1 && self.rings[0].len() == 1
+    }
+}
+
+impl UtmZone {
+    pub fn from_epsg(epsg: u32) -> Option<UtmZone> {
+        match epsg {
+            32601..=32660 => Some(UtmZone { zone: epsg - 32600, north: true }),
+            32701..=32760 => Some(UtmZone { zone: epsg - 32700, north: false }),
+            _ => None,
+        }
+    }
+
+    fn central_meridian(&self) -> f64 {
+        (self.zone as f64 * 6.0 - 183.0).to_radians()
+    }
+
+    fn false_northing(&self) -> f64 {
+        if self.north { 0.0 } else { FALSE_NORTHING_SOUTH }
+    }
+}
+
+/// Constants of the Krüger series, see Karney (2011) "Transverse Mercator with an accuracy of a few
+/// nanometers". Three terms are good to well under a millimeter inside a zone.
+struct Series {
+    a: f64,
+    alpha: [f64; 3],
+    beta: [f64; 3],
+    delta: [f64; 3],
+}
+
+fn series() -> Series {
+    let n = F / (2.0 - F);
+    let n2 = n * n;
+    let n3 = n2 * n;
+
+    Series {
+        a: A / (1.0 + n) * (1.0 + n2 / 4.0 + n2 * n2 / 64.0),
+        alpha: [n / 2.0 - 2.0 * n2 / 3.0 + 5.0 * n3 / 16.0, 13.0 * n2 / 48.0 - 3.0 * n3 / 5.0, 61.0 * n3 / 240.0],
+        beta: [n / 2.0 - 2.0 * n2 / 3.0 + 37.0 * n3 / 96.0, n2 / 48.0 + n3 / 15.0, 17.0 * n3 / 480.0],
+        delta: [2.0 * n - 2.0 * n2 / 3.0 - 2.0 * n3, 7.0 * n2 / 3.0 - 8.0 * n3 / 5.0, 56.0 * n3 / 15.0],
+    }
+}
+
+/// Longitude and latitude in degrees to easting and northing in meters
+pub fn to_utm(lon: f64, lat: f64, zone: UtmZone) -> (f64, f64) {
+    let s = series();
+    let n = F / (2.0 - F);
+    let e = 2.0 * n.sqrt() / (1.0 + n);
+
+    let phi = lat.to_radians();
+    let lambda = lon.to_radians() - zone.central_meridian();
+
+    let t = (phi.sin().atanh() - e * (e * phi.sin()).atanh()).sinh();
+    let xi_prime = t.atan2(lambda.cos());
+    let eta_prime = (lambda.sin() / (1.0 + t * t).sqrt()).atanh();
+
+    let mut xi = xi_prime;
+    let mut eta = eta_prime;
+    for (j, alpha) in s.alpha.iter().enumerate() {
+        let k = 2.0 * (j + 1) as f64;
+        xi += alpha * (k * xi_prime).sin() * (k * eta_prime).cosh();
+        eta += alpha * (k * xi_prime).cos() * (k * eta_prime).sinh();
+    }
+
+    (FALSE_EASTING + K0 * s.a * eta, zone.false_northing() + K0 * s.a * xi)
+}
+
+/// Easting and northing in meters to longitude and latitude in degrees
+pub fn from_utm(easting: f64, northing: f64, zone: UtmZone) -> (f64, f64) {
+    let s = series();
+
+    let xi = (northing - zone.false_northing()) / (K0 * s.a);
+    let eta = (easting - FALSE_EASTING) / (K0 * s.a);
+
+    let mut xi_prime = xi;
+    let mut eta_prime = eta;
+    for (j, beta) in s.beta.iter().enumerate() {
+        let k = 2.0 * (j + 1) as f64;
+        xi_prime -= beta * (k * xi).sin() * (k * eta).cosh();
+        eta_prime -= beta * (k * xi).cos() * (k * eta).sinh();
+    }
+
+    let chi = (xi_prime.sin() / eta_prime.cosh()).asin();
+
+    let mut phi = chi;
+    for (j, delta) in s.delta.iter().enumerate() {
+        phi += delta * (2.0 * (j + 1) as f64 * chi).sin();
+    }
+
+    let lambda = zone.central_meridian() + eta_prime.sinh().atan2(xi_prime.cos());
+
+    (lambda.to_degrees(), phi.to_degrees())
+}
+
+/// Spherical (web) mercator meters to longitude and latitude in degrees
+pub fn from_web_mercator(x: f64, y: f64) -> (f64, f64) {
+    let lon = (x / A).to_degrees();
+    let lat = (2.0 * (y / A).exp().atan() - PI / 2.0).to_degrees();
+
+    (lon, lat)
+}
+
+fn parse_ring(ring: &serde_json::Value) -> Vec<(f64, f64)> {
+    ring.as_array()
+        .map(|points| {
+            points.iter()
+                .filter_map(|point| Some((point[0].as_f64()?, point[1].as_f64()?)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_geometry(geometry: &serde_json::Value, properties: &serde_json::Value) -> Option<Polygon> {
+    let rings = match geometry["type"].as_str()? {
+        // a point is a ring with a single corner, it covers the one pixel it falls in
+        "Point" => vec![vec![(geometry["coordinates"][0].as_f64()?, geometry["coordinates"][1].as_f64()?)]],
+        "Polygon" => geometry["coordinates"].as_array()?.iter().map(parse_ring).collect(),
+        // a multi polygon is treated as one zone
+        "MultiPolygon" => geometry["coordinates"].as_array()?
+            .iter()
+            .filter_map(|polygon| polygon.as_array())
+            .flatten()
+            .map(parse_ring)
+            .collect(),
+        _ => return None,
+    };
+
+    Some(Polygon { properties: properties.clone(), rings })
+}
+
+/// Pull every polygon out of a GeoJSON FeatureCollection, Feature or bare geometry. Anything that
+/// is not a point, polygon or multi polygon is skipped
+pub fn parse_polygons(geojson: &serde_json::Value) -> Vec<Polygon> {
+    match geojson["type"].as_str() {
+        Some("FeatureCollection") => geojson["features"]
+            .as_array()
+            .map(|features| {
+                features.iter()
+                    .filter_map(|feature| parse_geometry(&feature["geometry"], &feature["properties"]))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Some("Feature") => parse_geometry(&geojson["geometry"], &geojson["properties"]).into_iter().collect(),
+        _ => parse_geometry(geojson, &serde_json::Value::Null).into_iter().collect(),
+    }
+}
@@ -0,0 +1,407 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::task::spawn_blocking;
+
+use crate::cdse::http::CdseError;
+use crate::cdse::CDSE;
+use crate::encoding::Format;
+use crate::filters::enhance::{enhance_encoded, parse_enhancements};
+
+/// How long a job stays with the instance running it without being renewed. Running jobs renew it
+/// every third of that, so another instance only takes a job over from one that has gone
+const LEASE_SECS: u64 = 120;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    /// Waiting for the product to come back from the long term archive, tried again on its own
+    Pending,
+    Done,
+    Failed,
+}
+
+/// What a job makes, the same things /v2/fetch takes
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobRequest {
+    pub id: String,
+    pub filter: String,
+    pub scale: u32,
+    /// As given to Format::parse
+    pub format: String,
+    /// As given to parse_enhancements, empty for none
+    #[serde(default)]
+    pub enhance: String,
+    /// Shrink the result so neither side is larger
+    #[serde(default)]
+    pub max_size: Option<u32>,
+}
+
+impl JobRequest {
+    pub fn format(&self) -> Format {
+        Format::parse(self.format.as_str()).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub job_id: String,
+    pub state: JobState,
+    /// What the job is doing right now
+    pub stage: String,
+    /// From 0 to 1
+    pub progress: f64,
+    pub request: JobRequest,
+    /// Seconds since the epoch
+    pub created: u64,
+    pub error: Option<String>,
+    /// Instance running the job
+    #[serde(default)]
+    pub worker: Option<String>,
+    /// Until when (seconds since the epoch) the worker has the job
+    #[serde(default)]
+    pub lease_until: u64,
+}
+
+/// How trying to take a job for this instance went
+enum Claim {
+    Claimed(Job),
+    /// Another instance has it until then
+    Taken { until: u64 },
+    /// Finished, or not in the bucket at all
+    Gone,
+}
+
+/// Where a job is kept in the bucket
+fn job_file(job_id: &str) -> String {
+    format!("jobs/{job_id}.json")
+}
+
+/// Where the result of a finished job is kept in the bucket
+pub fn result_file(job: &Job) -> String {
+    format!("jobs/{}/result.{}", job.job_id, job.request.format().cache_suffix())
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+/// 128 random bits in hex. RandomState is seeded by the OS, so ids made by different instances, or
+/// by one instance before and after a restart, do not collide
+fn random_id() -> String {
+    let part = || {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u64(now());
+        hasher.finish()
+    };
+
+    format!("{:016x}{:016x}", part(), part())
+}
+
+/// Jobs are processed by a fixed number of workers in the order they came in. Every change of state
+/// is written to the bucket, so jobs survive restarts and can be looked up from any instance.
+/// Progress within a running job is only written when the lease is renewed. A worker claims a job
+/// in the bucket before running it, so instances sharing the bucket never run the same job twice
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    sender: UnboundedSender<String>,
+    receiver: Arc<tokio::sync::Mutex<UnboundedReceiver<String>>>,
+    /// Who we are when claiming jobs
+    instance: String,
+}
+
+impl Default for Jobs {
+    fn default() -> Jobs {
+        Jobs::new()
+    }
+}
+
+impl Jobs {
+    pub fn new() -> Jobs {
+        let (sender, receiver) = unbounded_channel();
+
+        Jobs {
+            jobs: Mutex::new(HashMap::new()),
+            sender,
+            receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
+            instance: random_id(),
+        }
+    }
+
+    /// Start the workers, on the runtime this is called from, and queue every unfinished job in the
+    /// bucket. Jobs another instance is running are only run here if that instance stops renewing
+    /// them
+    pub fn start(self: &Arc<Self>, cdse: Arc<CDSE>, workers: usize) {
+        for _ in 0..workers.max(1) {
+            let receiver = self.receiver.clone();
+            let jobs = self.clone();
+            let cdse = cdse.clone();
+
+            tokio::spawn(async move {
+                loop {
+                    let job_id = match receiver.lock().await.recv().await {
+                        Some(job_id) => job_id,
+                        None => break,
+                    };
+
+                    jobs.run(&cdse, job_id.as_str()).await;
+                }
+            });
+        }
+
+        let jobs = self.clone();
+        tokio::spawn(async move {
+            for name in cdse.list("jobs/").await.into_iter().filter(|x| x.ends_with(".json")) {
+                let job: Option<Job> = cdse.check_bucket_and_download(name.as_str()).await
+                    .and_then(|data| serde_json::from_slice(&data).ok());
+
+                // claiming it puts it in memory, until then it is read from the bucket
+                if let Some(job) = job.filter(|j| matches!(j.state, JobState::Queued | JobState::Running | JobState::Pending)) {
+                    let _ = jobs.sender.send(job.job_id);
+                }
+            }
+        });
+    }
+
+    /// Queue a new job and return it
+    pub async fn submit(&self, cdse: &CDSE, request: JobRequest) -> Job {
+        let job_id = random_id();
+
+        let job = Job {
+            job_id: job_id.clone(),
+            state: JobState::Queued,
+            stage: "queued".to_string(),
+            progress: 0.0,
+            request,
+            created: now(),
+            error: None,
+            worker: None,
+            lease_until: 0,
+        };
+
+        cdse.store(job_file(job_id.as_str()).as_str(), serde_json::to_vec(&job).unwrap()).await;
+
+        self.jobs.lock().unwrap().insert(job_id.clone(), job.clone());
+        let _ = self.sender.send(job_id);
+
+        job
+    }
+
+    /// A job from memory, or from the bucket if it was made before a restart or by another instance
+    pub async fn get(&self, cdse: &CDSE, job_id: &str) -> Option<Job> {
+        if let Some(job) = self.jobs.lock().unwrap().get(job_id) {
+            return Some(job.clone());
+        }
+
+        let data = cdse.check_bucket_and_download(job_file(job_id).as_str()).await?;
+
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Change a job in memory, returning the changed job
+    fn update(&self, job_id: &str, f: impl FnOnce(&mut Job)) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(job_id)?;
+
+        f(job);
+
+        Some(job.clone())
+    }
+
+    /// Change a job this instance runs and write it to the bucket, as long as the bucket still has
+    /// it as ours. It is written back with the generation it was read at, so an instance taking
+    /// the job over in between wins. Returns false when the job is no longer ours
+    async fn update_if_ours(&self, cdse: &CDSE, job_id: &str, f: impl FnOnce(&mut Job)) -> bool {
+        let name = job_file(job_id);
+
+        let generation = match cdse.storage().get_versioned(name.as_str()).await {
+            Some((data, generation)) => match serde_json::from_slice::<Job>(&data) {
+                Ok(job) if job.worker.as_deref() == Some(self.instance.as_str()) => generation,
+                _ => return false,
+            },
+            None => return false,
+        };
+
+        let job = match self.update(job_id, f) {
+            Some(job) => job,
+            None => return false,
+        };
+
+        let ours = cdse.storage().put_if_generation(name.as_str(), serde_json::to_vec(&job).unwrap(), generation).await;
+
+        // finished jobs are read back from the bucket, no need to hold on to them
+        if ours && (job.state == JobState::Done || job.state == JobState::Failed) {
+            self.jobs.lock().unwrap().remove(job_id);
+        }
+
+        ours
+    }
+
+    /// Take a job for this instance from its copy in the bucket. It is written back with the
+    /// generation it was read at, so when several instances try at once only one gets it
+    async fn claim(&self, cdse: &CDSE, job_id: &str) -> Claim {
+        let name = job_file(job_id);
+
+        let (mut job, generation) = match cdse.storage().get_versioned(name.as_str()).await {
+            Some((data, generation)) => match serde_json::from_slice::<Job>(&data) {
+                Ok(job) => (job, generation),
+                Err(_) => return Claim::Gone,
+            },
+            None => return Claim::Gone,
+        };
+
+        if job.state == JobState::Done || job.state == JobState::Failed {
+            return Claim::Gone;
+        }
+
+        let time = now();
+        if job.worker.as_ref().is_some_and(|w| *w != self.instance) && job.lease_until > time {
+            return Claim::Taken { until: job.lease_until };
+        }
+
+        job.state = JobState::Running;
+        job.stage = "starting".to_string();
+        job.progress = 0.0;
+        job.worker = Some(self.instance.clone());
+        job.lease_until = time + LEASE_SECS;
+
+        if !cdse.storage().put_if_generation(name.as_str(), serde_json::to_vec(&job).unwrap(), generation).await {
+            // someone got there first, look again once their lease would be up
+            return Claim::Taken { until: time + LEASE_SECS };
+        }
+
+        self.jobs.lock().unwrap().insert(job_id.to_string(), job.clone());
+
+        Claim::Claimed(job)
+    }
+
+    /// Send a job round the queue again after a while
+    fn requeue(self: &Arc<Self>, job_id: &str, after: Duration) {
+        let jobs = self.clone();
+        let job_id = job_id.to_string();
+
+        tokio::spawn(async move {
+            tokio::time::sleep(after).await;
+            let _ = jobs.sender.send(job_id);
+        });
+    }
+
+    async fn run(self: &Arc<Self>, cdse: &Arc<CDSE>, job_id: &str) {
+        let request = match self.claim(cdse, job_id).await {
+            Claim::Claimed(job) => job.request,
+            Claim::Taken { until } => {
+                // the other instance may go away before it is done
+                self.requeue(job_id, Duration::from_secs(until.saturating_sub(now()) + 1));
+                return;
+            }
+            Claim::Gone => {
+                self.jobs.lock().unwrap().remove(job_id);
+                return;
+            }
+        };
+
+        // processing panics on bad products, so keep it on its own task
+        let id = job_id.to_string();
+        let jobs = self.clone();
+        let cdse = cdse.clone();
+        let outcome = tokio::spawn(async move {
+            let work = async {
+                let format = request.format();
+
+                let progress = |stage: &str, progress: f64| {
+                    jobs.update(id.as_str(), |job| {
+                        job.stage = stage.to_string();
+                        job.progress = progress;
+                    });
+                };
+
+                let image = cdse.fetch_with_progress(request.id.as_str(), request.filter.as_str(), request.scale, format, &progress).await?;
+
+                // checked when the job was made
+                let steps = parse_enhancements(request.enhance.as_str()).unwrap_or_default();
+                let max_size = request.max_size;
+                let image = spawn_blocking(move || enhance_encoded(image, format, max_size, &steps)).await.unwrap();
+
+                progress("storing result", 0.9);
+
+                let job = jobs.update(id.as_str(), |_| {}).unwrap();
+                cdse.store(result_file(&job).as_str(), image).await;
+
+                Ok::<(), CdseError>(())
+            };
+
+            // keep the job ours, and its progress saved, for as long as it runs. Once another
+            // instance has taken it over the work is theirs
+            let renew = async {
+                loop {
+                    tokio::time::sleep(Duration::from_secs(LEASE_SECS / 3)).await;
+
+                    if !jobs.update_if_ours(&cdse, id.as_str(), |job| job.lease_until = now() + LEASE_SECS).await {
+                        break;
+                    }
+                }
+            };
+
+            tokio::select! {
+                outcome = work => Some(outcome),
+                _ = renew => None,
+            }
+        }).await;
+
+        let outcome = match outcome {
+            Ok(Some(outcome)) => Ok(outcome),
+            Ok(None) => {
+                warn!("Job {job_id} was taken over by another instance, stopped running it here");
+                self.jobs.lock().unwrap().remove(job_id);
+                return;
+            }
+            Err(e) => Err(e),
+        };
+
+        let stored = self.update_if_ours(cdse, job_id, |job| {
+            job.worker = None;
+            job.lease_until = 0;
+
+            match &outcome {
+                Ok(Ok(())) => {
+                    job.state = JobState::Done;
+                    job.stage = "done".to_string();
+                    job.progress = 1.0;
+                }
+                Ok(Err(CdseError::Pending { .. })) => {
+                    job.state = JobState::Pending;
+                    job.stage = "waiting for the archive".to_string();
+                    job.progress = 0.0;
+                }
+                Ok(Err(e)) => {
+                    job.state = JobState::Failed;
+                    job.stage = "failed".to_string();
+                    job.error = Some(e.to_string());
+                }
+                Err(_) => {
+                    job.state = JobState::Failed;
+                    job.stage = "failed".to_string();
+                    job.error = Some("Processing the product failed".to_string());
+                }
+            }
+        }).await;
+
+        if !stored {
+            warn!("Job {job_id} was taken over by another instance before it finished here");
+            self.jobs.lock().unwrap().remove(job_id);
+            return;
+        }
+
+        // the worker is not held up while the archive works, the job just comes back round later
+        if let Ok(Err(CdseError::Pending { retry_after, .. })) = outcome {
+            self.requeue(job_id, Duration::from_secs(retry_after));
+        }
+    }
+}
@@ -4,198 +4,486 @@
 #[macro_use]
 extern crate rocket;
 
-use std::io::Read;
-use std::net::IpAddr;
-use std::thread::spawn;
+use std::sync::Arc;
 
+use anyhow::Context;
 use base64::Engine;
 use base64::engine::general_purpose;
-use google_cloud_storage::client::{Client, ClientConfig};
-use google_cloud_storage::http::objects::download::Range;
-use google_cloud_storage::http::objects::get::GetObjectRequest;
-use lazy_static::lazy_static;
-use opencv::core::{Mat, MatTraitConst, Vector};
-use rocket::{Config, post};
-use serde::{Deserialize, Serialize};
-use xz2::read::XzEncoder;
-
+use opencv::prelude::Mat;
+use rocket::{Build, Config, Rocket, State, post};
+use rocket::fairing::AdHoc;
+use rocket::http::{ContentType, Status};
+use rocket::http::uri::Host;
+use serde::Serialize;
+use tokio::task::spawn_blocking;
+
+use crate::admin::Admin;
+use crate::analysis::change::{ChangeStats, detect_change};
+use crate::analysis::timeseries::{SeriesEntry, time_series, to_csv};
+use crate::analysis::zonal::{Layer, Zone, zonal_stats};
+use crate::cache::index::{self, EvictionPolicy};
 use crate::cdse::CDSE;
-use crate::cdse::search::{CDSESearch, search};
+use crate::cdse::http::CdseError;
+use crate::compression::{Coding, Encoded};
+use crate::encoding::Format;
+use crate::jobs::{JobRequest, JobState, Jobs};
+use crate::cdse::preview::PreviewSource;
+use crate::cdse::search::CDSESearch;
+use crate::filters::enhance::{Enhancement, enhance_encoded, enhance_jpg, parse_enhancements};
+use crate::filters::classify::{classify_water, Threshold, WaterStats};
+use crate::filters::index::Index;
+use crate::sat_data::{overview_for_size, valid_scale};
+use crate::settings::Settings;
 
 mod sat_data;
+mod sar;
+mod metadata;
+mod geo;
+mod tiles;
+mod encoding;
+mod compression;
+mod jobs;
+mod cache;
+mod admin;
+mod settings;
+mod credentials;
 pub mod filters;
 pub mod cdse;
+pub mod analysis;
 
-#[derive(Deserialize)]
-struct Keys {
-    cdse: CDSEKeys,
+#[derive(Serialize)]
+struct ImageReturn {
+    id: String,
+    image: String,
 }
 
-#[derive(Deserialize)]
-struct CDSEKeys {
-    username: String,
-    password: String,
+/// The usual error reply with what went wrong talking to CDSE, so clients can tell a rate limit
+/// from a product that will never work. Products coming back from the long term archive are
+/// "Pending" rather than "Error", with how long to wait before asking again
+#[derive(Serialize)]
+struct ErrorReturn {
+    #[serde(rename = "Result")]
+    result: &'static str,
+    #[serde(rename = "Error")]
+    error: String,
+    #[serde(rename = "Retry After", skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
+}
+
+fn cdse_error(e: &CdseError) -> Vec<u8> {
+    let (result, retry_after) = match e {
+        CdseError::Pending { retry_after, .. } => ("Pending", Some(*retry_after)),
+        CdseError::RateLimited { retry_after, .. } => ("Error", *retry_after),
+        _ => ("Error", None),
+    };
+
+    serde_json::to_vec(&ErrorReturn { result, error: e.to_string(), retry_after }).unwrap()
+}
+
+/// cdse_error as a v2 reply. Pending products answer 202 with a Retry-After header, searches
+/// that find nothing 404 and bad searches 400
+fn cdse_error_reply(e: &CdseError) -> Encoded {
+    match e {
+        CdseError::Pending { retry_after, .. } => Encoded::accepted(cdse_error(e), *retry_after),
+        CdseError::NotFound => Encoded::json(cdse_error(e)).with_status(Status::NotFound),
+        CdseError::BadRequest { .. } => Encoded::json(cdse_error(e)).with_status(Status::BadRequest),
+        _ => Encoded::json(cdse_error(e)),
+    }
 }
 
 #[derive(Serialize)]
-struct ImageReturn {
+struct ImageReturnV2 {
     id: String,
+}
+
+#[derive(Serialize)]
+struct ChangeReturn {
+    before: String,
+    after: String,
+    index: String,
+    threshold: f64,
+    stats: ChangeStats,
     image: String,
+    mask: String,
 }
 
 #[derive(Serialize)]
-struct ImageReturnV2 {
+struct WaterReturn {
     id: String,
+    index: String,
+    stats: WaterStats,
+    mask: String,
 }
 
-/// This will go and fetch the key file stored on the google bucket
-async fn fetch_keys_from_google() -> Keys {
-    // authenticate
-    let config = ClientConfig::default().with_auth().await.unwrap();
-    let client = Client::new(config);
+#[derive(Serialize)]
+struct StatsReturn {
+    id: String,
+    zones: Vec<Zone>,
+}
 
-    // download file
-    let file = client.download_object(&GetObjectRequest {
-        bucket: "satellite-storage".to_string(),
-        object: "keys.toml".to_string(),
-        ..Default::default()
-    }, &Range::default()).await.unwrap();
+#[derive(Serialize)]
+struct TimeSeriesReturn {
+    series: Vec<SeriesEntry>,
+    /// Whether the search found more products than a series takes
+    truncated: bool,
+}
 
-    // convert to string
-    let string_contents = std::str::from_utf8(file.as_slice()).unwrap();
+/// XZ compress and base64 an image, the way v1 clients expect it in their JSON
+pub fn compress_and_encode(image: &[u8], xz_level: u32) -> String {
+    let c = Coding::Xz.compress(image.to_vec(), xz_level);
 
-    // return
-    toml::from_str(string_contents).unwrap()
+    general_purpose::STANDARD.encode(c)
 }
 
+/// The image of a v1 reply. Old clients get the XZ payload they expect, while "Compression":
+/// "None" gets the image base64 encoded as it is
+fn v1_payload(image: &[u8], data: &serde_json::Value, xz_level: u32) -> String {
+    match data["Compression"].as_str() {
+        Some(compression) if compression.eq_ignore_ascii_case("none") => general_purpose::STANDARD.encode(image),
+        _ => compress_and_encode(image, xz_level),
+    }
+}
 
-lazy_static! {
-    static ref KEY_FILE: Keys = tokio::runtime::Runtime::new().unwrap().block_on(fetch_keys_from_google());
-    static ref CDSE_Instance: CDSE = tokio::runtime::Runtime::new().unwrap().block_on(cdse::CDSE::new(KEY_FILE.cdse.username.as_str(),KEY_FILE.cdse.password.as_str()));
+/// Enhancements of a v1 request, falling back to the old contrast value. None when "Enhance" is
+/// there but is not a pipeline we can read
+fn v1_enhancements(data: &serde_json::Value) -> Option<Vec<Enhancement>> {
+    if let Some(enhance) = data["Enhance"].as_str() {
+        parse_enhancements(enhance).ok()
+    } else if let Some(contrast) = data["Boost Contrast"].as_f64().filter(|c| *c != 1.0) {
+        Some(vec![Enhancement::Contrast(contrast)])
+    } else {
+        Some(Vec::new())
+    }
 }
 
-/// Gzip compress data
-fn compress(data: &[u8]) -> Vec<u8> {
-    let mut c = Vec::new();
-    let mut compressed = XzEncoder::new(data, 9);
-    compressed.read_to_end(&mut c).unwrap();
+async fn handle_image_return(cdse: &CDSE, settings: &Settings, data: &serde_json::Value, id: &str, steps: Vec<Enhancement>) -> Result<String, CdseError> {
+    // check filter
+    let filter = data["Filter"].as_str().unwrap_or(settings.render.default_filter.as_str());
+
+    let image = cdse.fetch(id, filter, 1, Format::default()).await?;
+
+    let image = spawn_blocking(move || enhance_jpg(image, &steps)).await.unwrap();
 
-    c
+    Ok(v1_payload(image.as_slice(), data, settings.render.xz_level))
 }
 
-pub fn compress_and_encode(image: &[u8]) -> String {
-    let c = compress(image);
+async fn handle_image_return_v2(cdse: &CDSE, id: &str, filter: &str, steps: &[Enhancement], scale: u32, max_size: Option<u32>, format: Format) -> Result<Vec<u8>, CdseError> {
+    let image = cdse.fetch(id, filter, scale, format).await?;
 
-    general_purpose::STANDARD.encode(c)
+    // resizing and enhancing work on the decoded image, raw arrays are sent as they are
+    if steps.is_empty() && max_size.is_none() {
+        return Ok(image);
+    }
+
+    let steps = steps.to_vec();
+
+    Ok(spawn_blocking(move || enhance_encoded(image, format, max_size, &steps)).await.unwrap())
 }
 
-fn handle_image_return(data: &serde_json::Value, id: &str) -> String {
-    // check filter
-    let filter_option = data["Filter"].clone();
+async fn search_with_json(cdse: &CDSE, data: &serde_json::Value) -> Result<String, CdseError> {
+    let s = parse_to_search(data);
 
-    let id_string = id.to_string();
+    // collect a list of search results
+    let search_results = cdse.search(s).await?;
 
-    // if one set, do what they want
-    let image_await = spawn(move || {
-        if let Some(filter) = filter_option.as_str() {
-            tokio::runtime::Runtime::new().unwrap().block_on(CDSE_Instance.fetch(id_string.as_str(), filter))
-        } else {
-            tokio::runtime::Runtime::new().unwrap().block_on(CDSE_Instance.fetch(id_string.as_str(), "True Color"))
-        }
-    }).join();
+    // default to the latest one
+    search_results.into_iter().next().map(|x| x.id).ok_or(CdseError::NotFound)
+}
+
+fn parse_to_search(data: &serde_json::Value) -> CDSESearch {
+    // create search requirements
+    let satellite = data["Satellite"].as_str().unwrap_or("SENTINEL-2").to_uppercase();
 
-    let mut image = image_await.unwrap();
+    // radar sees through clouds, and Sentinel 1 products have no cloud cover to filter on
+    let max_cloud_cover = data["Max Cloud Coverage"].as_f64().filter(|_| satellite != "SENTINEL-1");
+
+    let mut s = CDSESearch {
+        satellite: Some(satellite),
+        geojson: None,
+        max_cloud_cover,
+        start_date: data["Start Date"].as_str().map(str::to_string),
+        end_date: data["End Date"].as_str().map(str::to_string),
+        polarisation: data["Polarisation"].as_str().map(str::to_string),
+        mode: data["Mode"].as_str().map(str::to_string),
+    };
 
-    // check contrast value
-    let contrast_option = data["Boost Contrast"].as_f64();
+    // add geojson if present
+    if !data["GeoJson"].is_null() {
+        s.geojson = Some(data["GeoJson"].clone());
+    }
 
-    if let Some(contrast) = contrast_option {
-        if contrast != 1.0 {
-            let conv: Vector<u8> = Vector::from(image);
+    s
+}
 
-            let image_mat = opencv::imgcodecs::imdecode(&conv as _, opencv::imgcodecs::IMREAD_COLOR).unwrap();
+/// Whether a request searches Sentinel 1, whose products have none of the optical bands
+fn searches_radar(data: &serde_json::Value) -> bool {
+    data["Satellite"].as_str().is_some_and(|x| x.eq_ignore_ascii_case("SENTINEL-1"))
+}
 
-            let mut m = Mat::default();
-            image_mat.convert_to(&mut m, -1, contrast, 0.0).unwrap();
+/// Find the before and after products of a change request. Either they are given directly, or we
+/// take the latest product as of each date over the AOI, keeping both on the same tile
+async fn change_ids(cdse: &CDSE, data: &serde_json::Value) -> Option<(String, String)> {
+    if let (Some(before), Some(after)) = (data["Before"].as_str(), data["After"].as_str()) {
+        return Some((before.to_string(), after.to_string()));
+    }
 
-            let mut wtf = Vector::new();
+    let before_date = data["Before Date"].as_str()?;
+    let after_date = data["After Date"].as_str()?;
 
-            opencv::imgcodecs::imencode(".jpg", &m, &mut wtf, &Default::default()).unwrap();
+    // latest product between the two dates
+    let mut s = parse_to_search(data);
+    s.start_date = Some(before_date.to_string());
+    s.end_date = Some(after_date.to_string());
+    let after = cdse.search(s).await.ok()?.into_iter().next()?;
 
-            image = wtf.to_vec();
+    // latest product up to the before date on the same tile
+    let mut s = parse_to_search(data);
+    s.start_date = None;
+    s.end_date = Some(before_date.to_string());
+    let before = cdse.search(s).await.ok()?.into_iter().find(|x| x.tile_id() == after.tile_id())?;
+
+    Some((before.id, after.id))
+}
+
+/// Base64 an image for a v2 JSON reply. Only v1 replies are XZ compressed
+fn encode_image(extension: &str, image: &Mat) -> String {
+    let mut buffer = opencv::core::Vector::new();
+    opencv::imgcodecs::imencode(extension, image, &mut buffer, &Default::default()).unwrap();
+
+    general_purpose::STANDARD.encode(buffer.as_slice())
+}
+
+async fn handle_change(cdse: &CDSE, data: serde_json::Value) -> Option<ChangeReturn> {
+    if searches_radar(&data) {
+        return None;
+    }
+
+    let (before, after) = change_ids(cdse, &data).await?;
+
+    let index = Index::from_name(data["Index"].as_str().unwrap_or("NDWI"))?;
+    let threshold = data["Threshold"].as_f64().unwrap_or(0.1);
+
+    // indexes need optical bands, radar products have none
+    let before_data = cdse.sat_data(before.as_str()).await.ok().filter(|x| x.sar().is_none())?;
+    let after_data = cdse.sat_data(after.as_str()).await.ok().filter(|x| x.sar().is_none())?;
+
+    spawn_blocking(move || {
+        let change = detect_change(&before_data, &after_data, index, threshold).ok()?;
+
+        Some(ChangeReturn {
+            before,
+            after,
+            index: index.name().to_string(),
+            threshold,
+            image: encode_image(".jpg", &change.render_difference()),
+            mask: encode_image(".png", &change.render_mask()),
+            stats: change.stats,
+        })
+    }).await.unwrap()
+}
+
+/// This compares an index between two acquisitions of the same tile. Sentinel 1 is refused
+#[post("/v2/change", data = "<input>")]
+async fn api_v2_change(cdse: &State<Arc<CDSE>>, input: &str) -> Encoded {
+    let to_json: serde_json::error::Result<serde_json::Value> = serde_json::from_str(input);
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
+
+    if let Ok(json) = to_json {
+        let change = handle_change(cdse, json).await;
+
+        if let Some(to_return) = change {
+            Encoded::json(serde_json::to_vec(&to_return).unwrap())
+        } else {
+            Encoded::json(error)
         }
+    } else {
+        Encoded::json(error)
     }
+}
+
+/// This splits a product into water and land and returns the mask with the area of each. The
+/// threshold is either "otsu" (default) or an index value. Sentinel 1 products are refused
+#[get("/v2/water?<id>&<index>&<threshold>&<scl>")]
+async fn api_v2_water(cdse: &State<Arc<CDSE>>, id: &str, index: Option<&str>, threshold: Option<&str>, scl: Option<bool>) -> Encoded {
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
+
+    // only the water indexes make sense here
+    let index = match Index::from_name(index.unwrap_or("NDWI")) {
+        Some(i) if i == Index::Ndwi || i == Index::Mndwi => i,
+        _ => return Encoded::json(error),
+    };
+
+    let threshold = match Threshold::from_name(threshold.unwrap_or("otsu")) {
+        Some(t) => t,
+        None => return Encoded::json(error),
+    };
 
-    return compress_and_encode(image.as_slice());
+    let sat_data = match cdse.sat_data(id).await {
+        Ok(sat_data) if sat_data.sar().is_some() => return Encoded::json(error),
+        Ok(sat_data) => sat_data,
+        Err(e) => return cdse_error_reply(&e),
+    };
+
+    let id = id.to_string();
+
+    let to_return = spawn_blocking(move || {
+        let classification = classify_water(&sat_data, index, threshold, scl.unwrap_or(true));
+
+        WaterReturn {
+            id,
+            index: index.name().to_string(),
+            mask: encode_image(".png", &classification.mask),
+            stats: classification.stats,
+        }
+    }).await.unwrap();
+
+    Encoded::json(serde_json::to_vec(&to_return).unwrap())
+}
+
+/// Read the requested percentiles, defaulting to the quartiles and the 10th and 90th percentiles
+fn parse_percentiles(data: &serde_json::Value) -> Vec<f64> {
+    data["Percentiles"].as_array()
+        .map(|p| p.iter().filter_map(|x| x.as_f64()).collect())
+        .unwrap_or_else(|| vec![10.0, 25.0, 75.0, 90.0])
 }
 
-fn handle_image_return_v2(id: &str, filter: &str, contrast:f32) -> Vec<u8> {
+async fn handle_stats(cdse: &CDSE, data: serde_json::Value) -> Option<StatsReturn> {
+    let id = data["ID"].as_str()?.to_string();
+    let layer = Layer::from_json(&data).ok()?;
+    let polygons = geo::parse_polygons(&data["GeoJson"]);
 
-    let id_string = id.to_string();
+    let percentiles = parse_percentiles(&data);
 
-    let filter_clone = filter.to_string();
+    if polygons.is_empty() {
+        return None;
+    }
 
-    // if one set, do what they want
-    let image_await = spawn(move || {
-        tokio::runtime::Runtime::new().unwrap().block_on(CDSE_Instance.fetch(id_string.as_str(), filter_clone.as_str()))
-    }).join();
+    // indexes and bands need optical products
+    let sat_data = cdse.sat_data(id.as_str()).await.ok().filter(|x| x.sar().is_none())?;
 
-    let mut image = image_await.unwrap();
+    let cloud_mask = data["Cloud Mask"].as_bool().unwrap_or(false);
 
-    // check contrast value
-    let conv: Vector<u8> = Vector::from(image);
+    let zones = spawn_blocking(move || zonal_stats(&sat_data, &layer, &polygons, &percentiles, cloud_mask)).await.unwrap().ok()?;
 
-    let mut image_mat = opencv::imgcodecs::imdecode(&conv as _, opencv::imgcodecs::IMREAD_COLOR).unwrap();
+    Some(StatsReturn { id, zones })
+}
 
-    dbg!(contrast as f64);
+/// This returns statistics of an index or band expression over every polygon of the GeoJson.
+/// Sentinel 1 products are refused
+#[post("/v2/stats", data = "<input>")]
+async fn api_v2_stats(cdse: &State<Arc<CDSE>>, input: &str) -> Encoded {
+    let to_json: serde_json::error::Result<serde_json::Value> = serde_json::from_str(input);
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
 
-    if contrast != 1.0 {
-        let mut m = Mat::default();
-        image_mat.convert_to(&mut m, opencv::core::CV_8UC3, contrast as f64, 0.0).unwrap();
-        image_mat = m;
+    if let Ok(json) = to_json {
+        let stats = handle_stats(cdse, json).await;
 
+        if let Some(to_return) = stats {
+            Encoded::json(serde_json::to_vec(&to_return).unwrap())
+        } else {
+            Encoded::json(error)
+        }
+    } else {
+        Encoded::json(error)
     }
+}
 
+async fn handle_time_series(cdse: &CDSE, data: serde_json::Value) -> Option<Encoded> {
+    let layer = Layer::from_json(&data).ok()?;
+    let polygons = geo::parse_polygons(&data["GeoJson"]);
+    let percentiles = parse_percentiles(&data);
 
-    let mut wtf = Vector::new();
+    // a season of data, not the whole archive
+    data["Start Date"].as_str()?;
+    data["End Date"].as_str()?;
 
-    opencv::imgcodecs::imencode(".jpg", &image_mat, &mut wtf, &Default::default()).unwrap();
+    if polygons.is_empty() || searches_radar(&data) {
+        return None;
+    }
 
-    image = wtf.to_vec();
+    let found = match cdse.search_all(parse_to_search(&data)).await {
+        Ok(found) => found,
+        Err(e) => return Some(cdse_error_reply(&e)),
+    };
+
+    let series = time_series(cdse, found.products, &layer, &polygons, &percentiles).await;
 
-    return compress(image.as_slice());
+    // CSV has nowhere else to say the series was cut short
+    if data["Format"].as_str() == Some("CSV") {
+        Some(Encoded::new(ContentType::CSV, to_csv(&series, &percentiles).into_bytes())
+            .with_header("Series-Truncated", found.truncated.to_string()))
+    } else {
+        Some(Encoded::json(serde_json::to_vec(&TimeSeriesReturn { series, truncated: found.truncated }).unwrap()))
+    }
 }
 
-fn search_with_json(data: &serde_json::Value) -> String {
-    let s = parse_to_search(data);
+/// This returns statistics of an index over the GeoJson for every product between the start and
+/// end date, as JSON or as CSV when "Format" is "CSV". Sentinel 1 is refused. At most 1000 products
+/// are used, "truncated" (the Series-Truncated header for CSV) says when there were more
+#[post("/v2/timeseries", data = "<input>")]
+async fn api_v2_time_series(cdse: &State<Arc<CDSE>>, input: &str) -> Encoded {
+    let to_json: serde_json::error::Result<serde_json::Value> = serde_json::from_str(input);
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
 
-    // collect a list of search results
-    let search_results = search(s);
+    if let Ok(json) = to_json {
+        handle_time_series(cdse, json).await.unwrap_or_else(|| Encoded::json(error))
+    } else {
+        Encoded::json(error)
+    }
+}
 
-    // default to the latest one
-    search_results[0].id.clone()
+async fn handle_tile(cdse: &CDSE, id: &str, filter: &str, z: u32, x: u32, y: u32) -> Option<Vec<u8>> {
+    let name = tiles::tile_cache_name(id, filter, z, x, y);
+
+    if let Some(tile) = cdse.check_bucket_and_download(name.as_str()).await {
+        return Some(tile.to_vec());
+    }
+
+    let metadata = cdse.metadata(id).await?;
+
+    // this is the whole product tile, usually already rendered in the bucket. zoomed out tiles
+    // use an overview
+    let image = cdse.fetch(id, filter, tiles::overview_for_zoom(z), Format::default()).await.ok()?;
+
+    let tile = spawn_blocking(move || {
+        let image_mat = opencv::imgcodecs::imdecode(&opencv::core::Vector::from(image), opencv::imgcodecs::IMREAD_UNCHANGED).unwrap();
+
+        tiles::encode_tile(&tiles::render_tile(&image_mat, &metadata, z, x, y))
+    }).await.unwrap();
+
+    cdse.store(name.as_str(), tile.clone()).await;
+
+    Some(tile)
 }
 
-fn parse_to_search(data: &serde_json::Value) -> CDSESearch {
-    // create search requirements
-    let mut s = CDSESearch {
-        satellite: Some("SENTINEL-2".parse().unwrap()),
-        geojson: None,
-        max_cloud_cover: data["Max Cloud Coverage"].as_f64(),
-    };
+/// XYZ web map tiles of a filter, in web mercator
+#[get("/tiles/<id>/<filter>/<z>/<x>/<y>")]
+async fn api_tiles(cdse: &State<Arc<CDSE>>, id: &str, filter: &str, z: u32, x: u32, y: &str) -> Option<(ContentType, Vec<u8>)> {
+    let y: u32 = y.strip_suffix(".png").unwrap_or(y).parse().ok()?;
 
-    // add geojson if present
-    if let Ok(test) = serde_json::from_value(data["GeoJson"].clone()) {
-        s.geojson = Some(test);
+    if !tiles::valid_tile(z, x, y) {
+        return None;
     }
 
-    s
+    let tile = handle_tile(cdse, id, filter, z, x, y).await?;
+
+    Some((ContentType::PNG, tile))
 }
 
+/// WMTS GetCapabilities document pointing at the XYZ tiles of a filter. Set server.public_url when
+/// the service is reached through a proxy
+#[get("/tiles/<id>/<filter>/WMTSCapabilities.xml")]
+async fn api_tiles_capabilities(cdse: &State<Arc<CDSE>>, settings: &State<Settings>, id: &str, filter: &str, host: &Host<'_>) -> Option<Encoded> {
+    let base_url = settings.server.public_url.clone().unwrap_or_else(|| format!("http://{host}"));
+
+    let metadata = cdse.metadata(id).await?;
+
+    Some(Encoded::new(ContentType::XML, tiles::capabilities(base_url.as_str(), id, filter, &metadata).into_bytes()))
+}
 
 /// This will only fetch new images from ESA
 #[post("/v2", data = "<input>")]
-async fn api_v2_endpoint(input: &str) -> Vec<u8> {
+async fn api_v2_endpoint(cdse: &State<Arc<CDSE>>, input: &str) -> Encoded {
     // there are two commands here, new and change. New will get and fetch an image with search
     // criteria and change will get an already existing image out of storage
 
@@ -204,24 +492,187 @@ async fn api_v2_endpoint(input: &str) -> Vec<u8> {
 
     if let Ok(json) = to_json {
         // default to new search
-        let id = search_with_json(&json);
+        match search_with_json(cdse, &json).await {
+            Ok(id) => Encoded::json(serde_json::to_vec(&ImageReturnV2 { id }).unwrap()),
+            Err(e) => cdse_error_reply(&e),
+        }
+    } else {
+        Encoded::json(error)
+    }
+}
+
+/// THis will fetch image from storage. Enhancements are a comma separated pipeline such as
+/// "stretch:2:98,gamma:1.2". scale (2, 4, 8 or 16) picks a reduced overview, or max_size picks the
+/// nearest overview and shrinks it so neither side is larger. format is one of "jpeg[:quality]",
+/// "png[:compression]", "webp", "tiff" (16 bit, the product's own values for band composites) or
+/// "npy" (32 bit floats, raw index values for index names). Sentinel 1 products take "VV", "VH", "VV-VH" or "SAR RGB" (the default), as dB with npy.
+/// The image comes back as it is, compressed only as Accept-Encoding allows. Send
+/// "Accept-Encoding: xz" for the old XZ payloads. Products in the long term archive are ordered and
+/// answer 202 "Pending" with a "Retry After" in seconds, also sent as a Retry-After header, until
+/// CDSE has them back
+#[get("/v2/fetch?<id>&<filter>&<enhance>&<scale>&<max_size>&<format>")]
+async fn api_v2_fetch(cdse: &State<Arc<CDSE>>, id: &str, filter: &str, enhance: Option<&str>, scale: Option<u32>, max_size: Option<u32>, format: Option<&str>) -> Encoded {
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
 
-        let to_return = ImageReturnV2 { id };
+    let scale = scale.unwrap_or_else(|| max_size.map(overview_for_size).unwrap_or(1));
+    if !valid_scale(scale) || max_size == Some(0) {
+        return Encoded::json(error);
+    }
 
-        serde_json::to_vec(&to_return).unwrap()
+    let format = if let Some(format) = format {
+        match Format::parse(format) {
+            Ok(format) => format,
+            Err(_) => return Encoded::json(error),
+        }
     } else {
-        error
+        Format::default()
+    };
+
+    match parse_enhancements(enhance.unwrap_or_default()) {
+        // tiffs and raw arrays are values, not pictures, so they cannot be enhanced
+        Ok(steps) if format.can_enhance() || steps.is_empty() => {
+            match handle_image_return_v2(cdse, id, filter, &steps, scale, max_size, format).await {
+                Ok(image) => Encoded::new(format.content_type(), image),
+                Err(e) => cdse_error_reply(&e),
+            }
+        }
+        _ => Encoded::json(error),
+    }
+}
+
+/// Fast jpg preview without downloading the product. source is "quicklook" (the default, a few
+/// hundred pixels) or "tci" for the product's own true colour image, shrunk to fit size
+#[get("/v2/preview?<id>&<source>&<size>")]
+async fn api_v2_preview(cdse: &State<Arc<CDSE>>, id: &str, source: Option<&str>, size: Option<u32>) -> Option<(ContentType, Vec<u8>)> {
+    let source = PreviewSource::from_name(source.unwrap_or("quicklook"))?;
+    let size = size.unwrap_or(1024).max(1);
+
+    let image = cdse.preview(id, source, size).await?;
+
+    Some((ContentType::JPEG, image))
+}
+
+/// Read a job from a request, the same values /v2/fetch takes. Without an ID the latest product
+/// matching the search criteria is used
+async fn parse_job_request(cdse: &CDSE, settings: &Settings, data: &serde_json::Value) -> Option<JobRequest> {
+    let max_size = data["Max Size"].as_u64().map(|x| x as u32);
+    let scale = data["Scale"].as_u64()
+        .map(|x| x as u32)
+        .unwrap_or_else(|| max_size.map(overview_for_size).unwrap_or(1));
+
+    let format = data["Format"].as_str().unwrap_or("jpeg").to_string();
+    let enhance = data["Enhance"].as_str().unwrap_or_default().to_string();
+
+    if !valid_scale(scale) || max_size == Some(0) {
+        return None;
+    }
+
+    // tiffs and raw arrays are values, not pictures, so they cannot be enhanced
+    match (Format::parse(format.as_str()), parse_enhancements(enhance.as_str())) {
+        (Ok(format), Ok(steps)) if format.can_enhance() || steps.is_empty() => {}
+        _ => return None,
+    }
+
+    let id = if let Some(id) = data["ID"].as_str() {
+        id.to_string()
+    } else {
+        search_with_json(cdse, data).await.ok()?
+    };
+
+    Some(JobRequest {
+        id,
+        filter: data["Filter"].as_str().unwrap_or(settings.render.default_filter.as_str()).to_string(),
+        scale,
+        format,
+        enhance,
+        max_size,
+    })
+}
+
+/// Queue a fetch to run in the background and return the job straight away, for products that
+/// would take longer to process than a client will wait
+#[post("/v2/jobs", data = "<input>")]
+async fn api_v2_jobs(cdse: &State<Arc<CDSE>>, jobs: &State<Arc<Jobs>>, settings: &State<Settings>, input: &str) -> Encoded {
+    let to_json: serde_json::error::Result<serde_json::Value> = serde_json::from_str(input);
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
+
+    if let Ok(json) = to_json {
+        if let Some(request) = parse_job_request(cdse, settings, &json).await {
+            let job = jobs.submit(cdse, request).await;
+
+            Encoded::json(serde_json::to_vec(&job).unwrap())
+        } else {
+            Encoded::json(error)
+        }
+    } else {
+        Encoded::json(error)
     }
 }
 
-/// THis will fetch image from storage
-#[get("/v2/fetch?<id>&<filter>&<contrast>")]
-async fn api_v2_fetch(id: &str,filter: &str, contrast:f32) -> Vec<u8> {
-    handle_image_return_v2(id,filter,contrast)
+/// State and progress of a job
+#[get("/v2/jobs/<job_id>")]
+async fn api_v2_job(cdse: &State<Arc<CDSE>>, jobs: &State<Arc<Jobs>>, job_id: &str) -> Option<Encoded> {
+    let job = jobs.get(cdse, job_id).await?;
+
+    Some(Encoded::json(serde_json::to_vec(&job).unwrap()))
+}
+
+/// The image a finished job made, in the format it asked for
+#[get("/v2/jobs/<job_id>/result")]
+async fn api_v2_job_result(cdse: &State<Arc<CDSE>>, jobs: &State<Arc<Jobs>>, job_id: &str) -> Option<Encoded> {
+    let job = jobs.get(cdse, job_id).await.filter(|j| j.state == JobState::Done)?;
+    let image = cdse.check_bucket_and_download(jobs::result_file(&job).as_str()).await?;
+
+    Some(Encoded::new(job.request.format().content_type(), image.to_vec()))
+}
+
+/// Size and use of what is kept in the bucket
+#[get("/admin/cache")]
+async fn api_admin_cache(_admin: Admin, cdse: &State<Arc<CDSE>>) -> Encoded {
+    let stats = index::stats(cdse.storage().index());
+
+    Encoded::json(serde_json::to_vec(&stats).unwrap())
+}
+
+/// Run eviction now. Takes the same fields as EvictionPolicy, with the configured policy used when
+/// there is no body
+#[post("/admin/cache/evict", data = "<input>")]
+async fn api_admin_cache_evict(_admin: Admin, cdse: &State<Arc<CDSE>>, settings: &State<Settings>, input: &str) -> Encoded {
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
+
+    let policy = if input.trim().is_empty() {
+        EvictionPolicy::from_settings(&settings.eviction)
+    } else {
+        match serde_json::from_str(input) {
+            Ok(policy) => policy,
+            Err(_) => return Encoded::json(error),
+        }
+    };
+
+    let report = index::evict(cdse.storage(), &policy).await;
+
+    Encoded::json(serde_json::to_vec(&report).unwrap())
+}
+
+/// Remove cached renders and tiles of a product, of a filter, or both, so they are made again on the next
+/// fetch. At least one of them is needed
+#[post("/admin/cache/purge?<id>&<filter>")]
+async fn api_admin_cache_purge(_admin: Admin, cdse: &State<Arc<CDSE>>, id: Option<&str>, filter: Option<&str>) -> Encoded {
+    let error: Vec<u8> = (serde_json::from_str("{\"Result\":\"Error\"}") as serde_json::error::Result<serde_json::Value>).unwrap().to_string().into_bytes();
+
+    if id.is_none() && filter.is_none() {
+        return Encoded::json(error);
+    }
+
+    let report = cdse.purge_renders(id, filter).await;
+
+    Encoded::json(serde_json::to_vec(&report).unwrap())
 }
 
+/// The original API. The image is XZ compressed and base64 encoded in the JSON unless the request
+/// sets "Compression" to "None"
 #[post("/v1", data = "<input>")]
-async fn api_v1_endpoint(input: &str) -> Vec<u8> {
+async fn api_v1_endpoint(cdse: &State<Arc<CDSE>>, settings: &State<Settings>, input: &str) -> Vec<u8> {
     // there are two commands here, new and change. New will get and fetch an image with search
     // criteria and change will get an already existing image out of storage
 
@@ -230,27 +681,34 @@ async fn api_v1_endpoint(input: &str) -> Vec<u8> {
 
 
     if let Ok(json) = to_json {
+        // a bad pipeline is an error, as it is for v2, rather than the image without it
+        let steps = match v1_enhancements(&json) {
+            Some(steps) => steps,
+            None => return error,
+        };
+
         if let Some(command) = json["Command"].as_str() {
             if command == "Change" {
                 // check if ID is set
                 if let Some(id) = json["ID"].as_str() {
-                    let image = handle_image_return(&json, id);
-
-                    let to_return = ImageReturn { id: id.to_string(), image };
-
-                    serde_json::to_vec(&to_return).unwrap()
+                    match handle_image_return(cdse, settings, &json, id, steps).await {
+                        Ok(image) => serde_json::to_vec(&ImageReturn { id: id.to_string(), image }).unwrap(),
+                        Err(e) => cdse_error(&e),
+                    }
                 } else {
                     error
                 }
             } else {
                 // default to new search
-                let id = search_with_json(&json);
-
-                let image = handle_image_return(&json, id.as_str());
-
-                let to_return = ImageReturn { id, image };
-
-                serde_json::to_vec(&to_return).unwrap()
+                let id = match search_with_json(cdse, &json).await {
+                    Ok(id) => id,
+                    Err(e) => return cdse_error(&e),
+                };
+
+                match handle_image_return(cdse, settings, &json, id.as_str(), steps).await {
+                    Ok(image) => serde_json::to_vec(&ImageReturn { id, image }).unwrap(),
+                    Err(e) => cdse_error(&e),
+                }
             }
         } else {
             error
@@ -262,37 +720,83 @@ async fn api_v1_endpoint(input: &str) -> Vec<u8> {
 
 
 #[post("/", data = "<input>")]
-async fn api_endpoint(input: &str) -> Vec<u8> {
+async fn api_endpoint(cdse: &State<Arc<CDSE>>, settings: &State<Settings>, input: &str) -> Vec<u8> {
     // convert request to json
     let check: serde_json::error::Result<serde_json::Value> = serde_json::from_str(input);
 
     // check if request is valid json
     if let Ok(data) = check {
-        let id = search_with_json(&data);
-
-        return handle_image_return(&data, id.as_str()).into_bytes();
+        if let Some(steps) = v1_enhancements(&data) {
+            if let Ok(id) = search_with_json(cdse, &data).await {
+                if let Ok(image) = handle_image_return(cdse, settings, &data, id.as_str(), steps).await {
+                    return image.into_bytes();
+                }
+            }
+        }
     }
 
     "Error".to_string().into_bytes()
 }
 
+/// Load the credentials and connect to CDSE and the bucket. Anything missing or unreachable stops
+/// the launch
+async fn connect(settings: &Settings) -> anyhow::Result<Arc<CDSE>> {
+    let credentials = credentials::load(settings).await.context("Could not load credentials")?;
+
+    let cdse = CDSE::new(settings, credentials.username.as_str(), credentials.password.as_str()).await?;
+
+    cdse.storage().check().await?;
+
+    Ok(Arc::new(cdse))
+}
+
+/// Build everything the handlers share and start the background work
+async fn ignite(rocket: Rocket<Build>) -> Result<Rocket<Build>, Rocket<Build>> {
+    let settings = rocket.state::<Settings>().unwrap().clone();
+
+    let cdse = match connect(&settings).await {
+        Ok(cdse) => cdse,
+        Err(e) => {
+            error!("Could not start: {e:#}");
+            return Err(rocket);
+        }
+    };
+
+    let jobs = Arc::new(Jobs::new());
+    jobs.start(cdse.clone(), settings.jobs.workers);
+
+    // keep the bucket index saved and evict on the configured interval
+    let storage = cdse.storage().clone();
+    let policy = EvictionPolicy::from_settings(&settings.eviction);
+    let interval = std::time::Duration::from_secs(settings.eviction.interval_hours * 3600);
+    tokio::spawn(index::maintain(storage, policy, interval));
+
+    // download products ordered from the long term archive once they are back
+    let poller = cdse.clone();
+    tokio::spawn(async move { poller.poll_orders().await });
+
+    Ok(rocket.manage(cdse).manage(jobs))
+}
+
 #[launch]
 fn rocket() -> _ {
-    println!("{}", KEY_FILE.cdse.username);
-
-    // get port number
-    let port = match std::env::var("PORT") {
-        Ok(port) => port,
-        _ => String::from("8000")
+    // bad settings stop us here rather than on the first request
+    let settings = match Settings::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("Bad settings: {e:#}");
+            std::process::exit(1);
+        }
     };
 
     let config = Config {
-        port: port.parse().unwrap(),
-        address: IpAddr::V4("0.0.0.0".parse().unwrap()),
+        port: settings.server.port,
+        address: settings.server.address,
         ..Default::default()
     };
 
     rocket::custom(config)
-        .mount("/", routes![api_endpoint, api_v1_endpoint, api_v2_endpoint, api_v2_fetch])
+        .manage(settings)
+        .attach(AdHoc::try_on_ignite("CDSE", ignite))
+        .mount("/", routes![api_endpoint, api_v1_endpoint, api_v2_endpoint, api_v2_fetch, api_v2_preview, api_v2_change, api_v2_water, api_v2_stats, api_v2_time_series, api_tiles, api_tiles_capabilities, api_v2_jobs, api_v2_job, api_v2_job_result, api_admin_cache, api_admin_cache_evict, api_admin_cache_purge])
 }
-
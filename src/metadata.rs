@@ -0,0 +1,102 @@
+use anyhow::Error;
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+
+use crate::geo::{from_utm, to_utm, UtmZone};
+
+/// The parts of a granule's MTD_TL.xml we care about: where the tile sits and how big a pixel is
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TileMetadata {
+    /// Military grid tile name, like "32TQM"
+    pub tile_id: String,
+    pub sensing_time: String,
+    pub epsg: u32,
+    /// Upper left corner of the tile in the tile's UTM projection
+    pub ulx: f64,
+    pub uly: f64,
+    /// Size of a pixel in meters at full (10 m) resolution
+    pub resolution: f64,
+    /// Number of pixels across the tile at full resolution
+    pub columns: u32,
+    pub rows: u32,
+}
+
+fn find<'a>(node: Node<'a, 'a>, tag: &str) -> anyhow::Result<Node<'a, 'a>> {
+    node.descendants()
+        .find(|n| n.has_tag_name(tag))
+        .ok_or_else(|| Error::msg(format!("Missing {tag} in tile metadata")))
+}
+
+fn find_text<'a>(node: Node<'a, 'a>, tag: &str) -> anyhow::Result<&'a str> {
+    find(node, tag)?
+        .text()
+        .map(str::trim)
+        .ok_or_else(|| Error::msg(format!("Empty {tag} in tile metadata")))
+}
+
+/// Find the element with the given tag that has resolution="10"
+fn find_10m<'a>(node: Node<'a, 'a>, tag: &str) -> anyhow::Result<Node<'a, 'a>> {
+    node.descendants()
+        .find(|n| n.has_tag_name(tag) && n.attribute("resolution") == Some("10"))
+        .ok_or_else(|| Error::msg(format!("Missing 10 m {tag} in tile metadata")))
+}
+
+impl TileMetadata {
+    /// Parse the contents of MTD_TL.xml
+    pub fn parse(xml: &str) -> anyhow::Result<TileMetadata> {
+        let doc = Document::parse(xml)?;
+        let root = doc.root();
+
+        // tile id looks like S2A_OPER_MSI_L1C_TL_2APS_20231010T130215_A043331_T32TQM_N05.09
+        let full_tile_id = find_text(root, "TILE_ID")?;
+        let tile_id = full_tile_id
+            .split('_')
+            .find(|part| part.len() == 6 && part.starts_with('T'))
+            .map(|part| part[1..].to_string())
+            .ok_or_else(|| Error::msg(format!("Unable to read tile from {full_tile_id}")))?;
+
+        let epsg = find_text(root, "HORIZONTAL_CS_CODE")?
+            .trim_start_matches("EPSG:")
+            .parse()?;
+
+        let size = find_10m(root, "Size")?;
+        let geoposition = find_10m(root, "Geoposition")?;
+
+        Ok(TileMetadata {
+            tile_id,
+            sensing_time: find_text(root, "SENSING_TIME")?.to_string(),
+            epsg,
+            ulx: find_text(geoposition, "ULX")?.parse()?,
+            uly: find_text(geoposition, "ULY")?.parse()?,
+            resolution: find_text(geoposition, "XDIM")?.parse()?,
+            columns: find_text(size, "NCOLS")?.parse()?,
+            rows: find_text(size, "NROWS")?.parse()?,
+        })
+    }
+
+    /// Size of one pixel in meters for an image of the given width covering the whole tile
+    pub fn pixel_size(&self, width: i32) -> f64 {
+        self.resolution * self.columns as f64 / width as f64
+    }
+
+    /// Area of one pixel in km² for an image of the given width covering the whole tile
+    pub fn pixel_area_km2(&self, width: i32) -> f64 {
+        self.pixel_size(width).powi(2) / 1_000_000.0
+    }
+
+    /// Longitude and latitude to a (column, row) position in an image of the given width covering
+    /// the whole tile. None if the tile is not in a UTM projection
+    pub fn to_pixel(&self, lon: f64, lat: f64, width: i32) -> Option<(f64, f64)> {
+        let (easting, northing) = to_utm(lon, lat, UtmZone::from_epsg(self.epsg)?);
+        let pixel_size = self.pixel_size(width);
+
+        Some(((easting - self.ulx) / pixel_size, (self.uly - northing) / pixel_size))
+    }
+
+    /// The other way around from to_pixel, a (column, row) position to longitude and latitude
+    pub fn from_pixel(&self, column: f64, row: f64, width: i32) -> Option<(f64, f64)> {
+        let pixel_size = self.pixel_size(width);
+
+        Some(from_utm(self.ulx + column * pixel_size, self.uly - row * pixel_size, UtmZone::from_epsg(self.epsg)?))
+    }
+}
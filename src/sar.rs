@@ -0,0 +1,218 @@
+use anyhow::Error;
+use opencv::core::{Vector, CV_32F};
+use opencv::imgcodecs::{IMREAD_ANYDEPTH, IMREAD_GRAYSCALE};
+use opencv::imgproc::INTER_AREA;
+use opencv::prelude::{Mat, MatTraitConst, MatTraitManual};
+use roxmltree::Document;
+
+use crate::sat_data::shrink;
+
+/// The polarisations we read from Sentinel 1 products. Horizontally sent (HH and HV) products are
+/// not supported
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Polarisation {
+    Vv,
+    Vh,
+}
+
+impl Polarisation {
+    /// From a measurement or calibration file name like
+    /// S1A_IW_GRDH_1SDV_20231010T054512_..._9A1C.SAFE/measurement/s1a-iw-grd-vh-20231010t054512-...-001.tiff
+    pub fn from_file_name(name: &str) -> Option<Polarisation> {
+        let file = name.rsplit('/').next()?;
+
+        if file.contains("-vv-") {
+            Some(Polarisation::Vv)
+        } else if file.contains("-vh-") {
+            Some(Polarisation::Vh)
+        } else {
+            None
+        }
+    }
+
+    /// Name of the band in overviews, like "VV.tif"
+    pub fn file_name(&self) -> &'static str {
+        match self {
+            Polarisation::Vv => "VV.tif",
+            Polarisation::Vh => "VH.tif",
+        }
+    }
+}
+
+/// Whether a file in a product zip is a GRD measurement image
+pub fn is_measurement(name: &str) -> bool {
+    name.contains("/measurement/") && name.ends_with(".tiff")
+}
+
+/// Whether a file in a product zip is a calibration table. The noise tables sit next to them
+pub fn is_calibration(name: &str) -> bool {
+    name.contains("/annotation/calibration/calibration-") && name.ends_with(".xml")
+}
+
+/// Linear interpolation of a table at x, holding the end values past either end
+fn interpolate(xs: &[f64], ys: &[f64], x: f64) -> f64 {
+    let i = xs.partition_point(|v| *v <= x);
+
+    if i == 0 {
+        ys[0]
+    } else if i == xs.len() {
+        ys[xs.len() - 1]
+    } else {
+        let t = (x - xs[i - 1]) / (xs[i] - xs[i - 1]);
+
+        ys[i - 1] + t * (ys[i] - ys[i - 1])
+    }
+}
+
+fn parse_numbers(text: Option<&str>) -> anyhow::Result<Vec<f64>> {
+    Ok(text.unwrap_or_default().split_whitespace().map(str::parse).collect::<Result<Vec<f64>, _>>()?)
+}
+
+/// The sigma0 calibration table of one image, from its calibration-*.xml. The table is given on a
+/// coarse grid of image lines, each with its own list of pixels, and sigma0 is DN² / A² with A
+/// interpolated from it
+pub struct Calibration {
+    lines: Vec<f64>,
+    /// Pixel positions and sigmaNought values, one list of each per line
+    vectors: Vec<(Vec<f64>, Vec<f64>)>,
+}
+
+impl Calibration {
+    pub fn parse(xml: &str) -> anyhow::Result<Calibration> {
+        let doc = Document::parse(xml)?;
+
+        let mut lines = Vec::new();
+        let mut vectors = Vec::new();
+
+        for vector in doc.descendants().filter(|n| n.has_tag_name("calibrationVector")) {
+            let child = |tag: &str| vector.children().find(|n| n.has_tag_name(tag)).and_then(|n| n.text());
+
+            let pixels = parse_numbers(child("pixel"))?;
+            let sigma_nought = parse_numbers(child("sigmaNought"))?;
+
+            if pixels.is_empty() || pixels.len() != sigma_nought.len() {
+                return Err(Error::msg("Calibration vector with mismatched pixels and sigmaNought"));
+            }
+
+            lines.push(child("line").unwrap_or_default().trim().parse()?);
+            vectors.push((pixels, sigma_nought));
+        }
+
+        if lines.is_empty() {
+            return Err(Error::msg("No calibration vectors"));
+        }
+
+        Ok(Calibration { lines, vectors })
+    }
+
+    /// Turn intensity (DN²) of an image reduced by scale into sigma0, in place. Every pixel is
+    /// calibrated at the centre of the full resolution pixels it was made from
+    fn apply(&self, intensity: &mut Mat, scale: u32) -> anyhow::Result<()> {
+        let to_full = |x: i32| (x as f64 + 0.5) * scale as f64 - 0.5;
+
+        // the table along each calibration line first, then between lines for every row
+        let columns: Vec<Vec<f64>> = self.vectors.iter()
+            .map(|(pixels, values)| (0..intensity.cols()).map(|c| interpolate(pixels, values, to_full(c))).collect())
+            .collect();
+
+        for row in 0..intensity.rows() {
+            let line = to_full(row);
+
+            // the calibration lines either side of this row, or the nearest one past either end
+            let (above, below, t) = match self.lines.partition_point(|l| *l <= line) {
+                0 => (0, 0, 0.0),
+                i if i == self.lines.len() => (i - 1, i - 1, 0.0),
+                i => (i - 1, i, (line - self.lines[i - 1]) / (self.lines[i] - self.lines[i - 1])),
+            };
+
+            for (c, value) in intensity.at_row_mut::<f32>(row)?.iter_mut().enumerate() {
+                let a = columns[above][c] + t * (columns[below][c] - columns[above][c]);
+
+                *value = (*value as f64 / (a * a)) as f32;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Decode a GRD measurement tiff and calibrate it to linear sigma0, reduced by scale. Intensity
+/// is averaged while shrinking, which takes out some speckle on the way
+pub fn calibrate(tiff: &[u8], calibration: &Calibration, scale: u32) -> anyhow::Result<Mat> {
+    let dn = opencv::imgcodecs::imdecode(&Vector::<u8>::from_slice(tiff), IMREAD_ANYDEPTH | IMREAD_GRAYSCALE)?;
+    if dn.empty() {
+        return Err(Error::msg("Unable to decode measurement"));
+    }
+
+    let mut dn_f32 = Mat::default();
+    dn.convert_to(&mut dn_f32, CV_32F, 1.0, 0.0)?;
+    drop(dn);
+
+    let mut intensity = Mat::default();
+    opencv::core::multiply(&dn_f32, &dn_f32, &mut intensity, 1.0, -1)?;
+    drop(dn_f32);
+
+    let mut intensity = shrink(&intensity, scale, INTER_AREA);
+    calibration.apply(&mut intensity, scale)?;
+
+    Ok(intensity)
+}
+
+/// Calibrated bands of a Sentinel 1 GRD product as linear sigma0, 32 bit floats in radar
+/// geometry. Single polarisation products only have VV
+#[derive(Clone)]
+pub struct SarBands {
+    pub vv: Option<Mat>,
+    pub vh: Option<Mat>,
+}
+
+impl SarBands {
+    /// Calibrate every measurement with the table of the same polarisation. Bands are done one
+    /// after the other, a full resolution band takes a few GB on the way
+    pub fn load(measurements: Vec<(Polarisation, Vec<u8>)>, calibrations: &[(Polarisation, String)], scale: u32) -> anyhow::Result<SarBands> {
+        let mut bands = SarBands { vv: None, vh: None };
+
+        for (polarisation, tiff) in measurements {
+            let xml = calibrations.iter()
+                .find(|(p, _)| *p == polarisation)
+                .map(|(_, xml)| xml.as_str())
+                .ok_or_else(|| Error::msg(format!("No calibration for {polarisation:?}")))?;
+
+            let sigma0 = calibrate(&tiff, &Calibration::parse(xml)?, scale)?;
+            bands.set(polarisation, sigma0);
+        }
+
+        if bands.vv.is_none() && bands.vh.is_none() {
+            return Err(Error::msg("Only VV and VH Sentinel 1 products are supported"));
+        }
+
+        Ok(bands)
+    }
+
+    pub fn get(&self, polarisation: Polarisation) -> Option<&Mat> {
+        match polarisation {
+            Polarisation::Vv => self.vv.as_ref(),
+            Polarisation::Vh => self.vh.as_ref(),
+        }
+    }
+
+    pub fn set(&mut self, polarisation: Polarisation, band: Mat) {
+        match polarisation {
+            Polarisation::Vv => self.vv = Some(band),
+            Polarisation::Vh => self.vh = Some(band),
+        }
+    }
+
+    /// Both bands with their polarisation, missing ones left out
+    pub fn bands(&self) -> impl Iterator<Item = (Polarisation, &Mat)> {
+        [Polarisation::Vv, Polarisation::Vh].into_iter().filter_map(|p| self.get(p).map(|m| (p, m)))
+    }
+
+    /// A copy with every band shrunk by factor
+    pub fn shrink(&self, factor: u32) -> SarBands {
+        SarBands {
+            vv: self.vv.as_ref().map(|m| shrink(m, factor, INTER_AREA)),
+            vh: self.vh.as_ref().map(|m| shrink(m, factor, INTER_AREA)),
+        }
+    }
+}
@@ -5,57 +5,338 @@ use std::thread::spawn;
 
 use anyhow::Error;
 use bytes::Bytes;
-use opencv::imgcodecs::IMREAD_GRAYSCALE;
-use opencv::prelude::Mat;
+use opencv::core::{Size, Vector, CV_16U, CV_8U};
+use opencv::imgcodecs::{IMREAD_ANYDEPTH, IMREAD_GRAYSCALE, IMREAD_UNCHANGED, IMREAD_REDUCED_GRAYSCALE_2, IMREAD_REDUCED_GRAYSCALE_4, IMREAD_REDUCED_GRAYSCALE_8};
+use opencv::imgproc::{INTER_AREA, INTER_NEAREST};
+use opencv::prelude::{Mat, MatTraitConst};
 use zip::ZipArchive;
 
+use crate::metadata::TileMetadata;
+use crate::sar::{self, Polarisation, SarBands};
+
+/// Band file endings. Level 1C products name bands like T32TQM_20231010T101031_B04.jp2, level 2A
+/// products keep a copy per resolution like T32TQM_20231010T101031_B04_10m.jp2 so we take the
+/// band's native one. B10 is not in level 2A products.
+const BAND_PATTERNS: [(&str, &str); 12] = [
+    ("_B01.jp2", "_B01_60m.jp2"),
+    ("_B02.jp2", "_B02_10m.jp2"),
+    ("_B03.jp2", "_B03_10m.jp2"),
+    ("_B04.jp2", "_B04_10m.jp2"),
+    ("_B05.jp2", "_B05_20m.jp2"),
+    ("_B06.jp2", "_B06_20m.jp2"),
+    ("_B07.jp2", "_B07_20m.jp2"),
+    ("_B08.jp2", "_B08_10m.jp2"),
+    ("_B09.jp2", "_B09_60m.jp2"),
+    ("_B10.jp2", "_B10.jp2"),
+    ("_B11.jp2", "_B11_20m.jp2"),
+    ("_B12.jp2", "_B12_20m.jp2"),
+];
+
+/// Bands are 15 bit digital numbers. OpenCV used to shift them down to 8 bit while decoding, the
+/// 8 bit getters keep giving those same values
+const DN_TO_U8: f64 = 1.0 / 128.0;
+
+/// Scene classification layer, only in level 2A products
+const SCL_PATTERN: &str = "_SCL_20m.jp2";
+
+/// Reduced resolution levels kept for every product, as how many times smaller than full size
+pub const OVERVIEW_SCALES: [u32; 4] = [2, 4, 8, 16];
+
+/// Width of a full resolution (10 m) tile in pixels. Every Sentinel 2 tile is 109.8 km across
+pub const FULL_SIZE: u32 = 10980;
+
+/// Pick the overview for an image no bigger than max_size: the smallest one that is still at least
+/// max_size across, so it only ever needs scaling down
+pub fn overview_for_size(max_size: u32) -> u32 {
+    OVERVIEW_SCALES.iter()
+        .rev()
+        .find(|scale| FULL_SIZE / **scale >= max_size)
+        .copied()
+        .unwrap_or(1)
+}
+
+/// Stored next to the bands of an overview once they are all written, listing their names
+pub const OVERVIEW_MANIFEST: &str = "manifest.json";
+
+/// Check the scale is full size or one of the overviews
+pub fn valid_scale(scale: u32) -> bool {
+    scale == 1 || OVERVIEW_SCALES.contains(&scale)
+}
+
 #[derive(Clone)]
 pub struct SatData {
     mat_array: Vec<Mat>,
+    scl: Option<Mat>,
+    metadata: Option<TileMetadata>,
+    /// How many times smaller than full resolution the bands are
+    scale: u32,
+    /// Calibrated bands of a Sentinel 1 product, in which case the optical bands are all empty
+    sar: Option<SarBands>,
 }
 
 unsafe impl Send for SatData {}
 
+// bands are never changed once loaded, and reading a Mat from several threads is safe
+unsafe impl Sync for SatData {}
+
+/// Shrink an image by a factor, averaging or (for classes) picking the nearest pixel
+pub(crate) fn shrink(m: &Mat, factor: u32, interpolation: i32) -> Mat {
+    if factor <= 1 || m.empty() {
+        return m.clone();
+    }
+
+    let size = m.size().unwrap();
+    let new_size = Size::new((size.width as u32 / factor).max(1) as i32, (size.height as u32 / factor).max(1) as i32);
+
+    let mut to_return = Mat::default();
+    opencv::imgproc::resize(m, &mut to_return, new_size, 0.0, 0.0, interpolation).unwrap();
+
+    to_return
+}
+
+/// Narrow a band of digital numbers to the 8 bit values the filters work on
+fn narrow(m: &Mat) -> Mat {
+    if m.depth() != CV_16U {
+        return m.clone();
+    }
+
+    let mut to_return = Mat::default();
+    m.convert_to(&mut to_return, CV_8U, DN_TO_U8, 0.0).unwrap();
+
+    to_return
+}
+
+/// Decode a jp2 on its own thread, reduced by the given scale, keeping its bit depth. OpenCV
+/// reduces by up to 8 while decoding, anything past that is shrunk afterwards
+fn decode_in_background(d: Vec<u8>, scale: u32) -> mpsc::Receiver<opencv::Result<Mat>> {
+    let (tx, rx) = mpsc::channel();
+
+    let (flags, remaining) = match scale {
+        2 => (IMREAD_REDUCED_GRAYSCALE_2, 1),
+        4 => (IMREAD_REDUCED_GRAYSCALE_4, 1),
+        s if s >= 8 => (IMREAD_REDUCED_GRAYSCALE_8, s / 8),
+        _ => (IMREAD_GRAYSCALE, 1),
+    };
+
+    spawn(move || {
+        let mat_data = Mat::from_slice(&d).unwrap();
+        tx.send(opencv::imgcodecs::imdecode(&mat_data, flags | IMREAD_ANYDEPTH).map(|m| shrink(&m, remaining, INTER_AREA))).unwrap();
+    });
+
+    rx
+}
+
 impl SatData {
     /// Create a new SatData instance from the unzipped file location
-    pub fn new(mut data: ZipArchive<Cursor<Bytes>>) -> anyhow::Result<SatData> {
-        // list of file patterns to look for
-        let file_patterns = ["_B01.jp2", "_B02.jp2", "_B03.jp2", "_B04.jp2", "_B05.jp2", "_B06.jp2", "_B07.jp2", "_B08.jp2", "_B09.jp2", "_B10.jp2", "_B11.jp2", "_B12.jp2"];
+    pub fn new(data: ZipArchive<Cursor<Bytes>>) -> anyhow::Result<SatData> {
+        SatData::new_reduced(data, 1)
+    }
+
+    /// Same as new, but with every band decoded at a reduced scale (one of OVERVIEW_SCALES).
+    /// Sentinel 1 GRD products are told apart by their measurement folder
+    pub fn new_reduced(mut data: ZipArchive<Cursor<Bytes>>, scale: u32) -> anyhow::Result<SatData> {
+        let mut thread_array = Vec::with_capacity(BAND_PATTERNS.len());
+        let mut scl_thread = None;
+        let mut metadata = None;
 
-        let mut thread_array = Vec::with_capacity(file_patterns.len());
-        let mut mat_array = Vec::with_capacity(file_patterns.len());
+        let mut measurements = Vec::new();
+        let mut calibrations = Vec::new();
+        let mut radar = false;
 
         for index in 0..data.len() {
             let mut file = data.by_index(index).unwrap();
 
-            for x in file_patterns {
-                if file.name().contains(x) && file.enclosed_name().unwrap().parent().unwrap().parent().unwrap().parent().unwrap().file_name().unwrap() == "GRANULE" {
+            if sar::is_measurement(file.name()) || sar::is_calibration(file.name()) {
+                radar = true;
+
+                // horizontal polarisations are skipped
+                let polarisation = match Polarisation::from_file_name(file.name()) {
+                    Some(p) => p,
+                    None => continue,
+                };
+
+                if sar::is_measurement(file.name()) {
                     let mut d = Vec::new();
                     file.read_to_end(&mut d).unwrap();
+                    measurements.push((polarisation, d));
+                } else {
+                    let mut xml = String::new();
+                    file.read_to_string(&mut xml).unwrap();
+                    calibrations.push((polarisation, xml));
+                }
+
+                continue;
+            }
+
+            // tile metadata sits next to the IMG_DATA folder in the granule
+            if file.name().ends_with("MTD_TL.xml") {
+                let mut xml = String::new();
+                file.read_to_string(&mut xml).unwrap();
+
+                metadata = TileMetadata::parse(xml.as_str()).ok();
+                continue;
+            }
+
+            // only look at images of the granule, not the quality or aux data
+            if !(file.name().contains("/GRANULE/") && file.name().contains("/IMG_DATA/")) {
+                continue;
+            }
+
+            if file.name().ends_with(SCL_PATTERN) {
+                let mut d = Vec::new();
+                file.read_to_end(&mut d).unwrap();
+
+                scl_thread = Some(decode_in_background(d, scale));
+                continue;
+            }
+
+            for (band, (l1c, l2a)) in BAND_PATTERNS.iter().enumerate() {
+                if file.name().ends_with(l1c) || file.name().ends_with(l2a) {
+                    let mut d = Vec::new();
+                    file.read_to_end(&mut d).unwrap();
+
+                    thread_array.push((band, decode_in_background(d, scale)));
+                }
+            }
+        }
+
+        if radar {
+            return Ok(SatData {
+                mat_array: vec![Mat::default(); BAND_PATTERNS.len()],
+                scl: None,
+                metadata: None,
+                scale,
+                sar: Some(SarBands::load(measurements, &calibrations, scale)?),
+            });
+        }
+
+        // missing bands are left empty
+        let mut mat_array = vec![Mat::default(); BAND_PATTERNS.len()];
+
+        for (band, x) in thread_array {
+            mat_array[band] = x.recv().unwrap()?;
+        }
+
+        let scl = match scl_thread {
+            Some(x) => Some(x.recv().unwrap()?),
+            None => None,
+        };
+
+        Ok(SatData {
+            mat_array,
+            scl,
+            metadata,
+            scale,
+            sar: None,
+        })
+    }
+
+    /// Rebuild an overview from band images saved with encode_bands
+    pub fn from_encoded_bands(files: Vec<(String, Bytes)>, metadata: Option<TileMetadata>, scale: u32) -> anyhow::Result<SatData> {
+        let mut mat_array = vec![Mat::default(); BAND_PATTERNS.len()];
+        let mut scl = None;
+        let mut sar: Option<SarBands> = None;
+
+        for (name, data) in files {
+            // radar bands are floats, so they are kept as tiff
+            if let Some(polarisation) = [Polarisation::Vv, Polarisation::Vh].into_iter().find(|p| p.file_name() == name) {
+                let m = opencv::imgcodecs::imdecode(&Vector::<u8>::from_slice(&data), IMREAD_UNCHANGED)?;
+                sar.get_or_insert(SarBands { vv: None, vh: None }).set(polarisation, m);
+                continue;
+            }
 
-                    let (tx, rx) = mpsc::channel();
+            let m = opencv::imgcodecs::imdecode(&Vector::<u8>::from_slice(&data), IMREAD_GRAYSCALE | IMREAD_ANYDEPTH)?;
 
-                    thread_array.push(rx);
+            if name == "SCL.png" {
+                scl = Some(m);
+            } else if let Some(band) = name.strip_prefix('B').and_then(|n| n.strip_suffix(".png")).and_then(|n| n.parse::<usize>().ok()) {
+                // older overviews were saved already narrowed to 8 bit
+                if m.depth() != CV_16U {
+                    return Err(Error::msg(format!("Overview band {name} is not 16 bit")));
+                }
 
-                    spawn(move || {
-                        let mat_data = Mat::from_slice(&d).unwrap();
-                        tx.send(opencv::imgcodecs::imdecode(&mat_data,IMREAD_GRAYSCALE)).unwrap();
-                    });
+                if (1..=BAND_PATTERNS.len()).contains(&band) {
+                    mat_array[band - 1] = m;
                 }
             }
         }
 
-        for x in thread_array {
-            mat_array.push(
-                x.recv().unwrap().unwrap()
-            )
+        // every filter should work on an overview, so it needs every band a product always has.
+        // B10 is not in level 2A products, and VV is in every dual and single polarisation one
+        match &sar {
+            Some(sar) if sar.vv.is_none() => return Err(Error::msg("Overview is missing VV")),
+            Some(_) => {}
+            None => {
+                if let Some(band) = (1..=BAND_PATTERNS.len()).filter(|band| *band != 10).find(|band| mat_array[band - 1].empty()) {
+                    return Err(Error::msg(format!("Overview is missing B{band:02}")));
+                }
+            }
         }
 
         Ok(SatData {
-            mat_array
+            mat_array,
+            scl,
+            metadata,
+            scale,
+            sar,
         })
     }
 
+    /// Every band (and the scene classification) as lossless png, named like "B04.png". Bands keep
+    /// their 16 bit digital numbers. Radar
+    /// bands are float tiffs named like "VV.tif". Missing bands are left out
+    pub fn encode_bands(&self) -> Vec<(String, Vec<u8>)> {
+        let mut files = Vec::with_capacity(BAND_PATTERNS.len() + 1);
+
+        let named = self.mat_array.iter()
+            .enumerate()
+            .map(|(band, m)| (format!("B{:02}.png", band + 1), m))
+            .chain(self.scl.iter().map(|m| ("SCL.png".to_string(), m)))
+            .chain(self.sar.iter().flat_map(|sar| sar.bands()).map(|(p, m)| (p.file_name().to_string(), m)));
+
+        for (name, m) in named {
+            if m.empty() {
+                continue;
+            }
+
+            let extension = if name.ends_with(".tif") { ".tif" } else { ".png" };
+
+            let mut buffer = Vector::new();
+            opencv::imgcodecs::imencode(extension, m, &mut buffer, &Default::default()).unwrap();
+
+            files.push((name, buffer.to_vec()));
+        }
+
+        files
+    }
+
+    /// A copy of this product at the given scale. Only works going smaller
+    pub fn overview(&self, scale: u32) -> SatData {
+        let factor = scale / self.scale;
+
+        SatData {
+            mat_array: self.mat_array.iter().map(|m| shrink(m, factor, INTER_AREA)).collect(),
+            scl: self.scl.as_ref().map(|m| shrink(m, factor, INTER_NEAREST)),
+            metadata: self.metadata.clone(),
+            scale,
+            sar: self.sar.as_ref().map(|sar| sar.shrink(factor)),
+        }
+    }
+
+    /// How many times smaller than full resolution the bands are
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Memory taken by the decoded bands
+    pub fn size_bytes(&self) -> usize {
+        self.mat_array.iter()
+            .chain(self.scl.iter())
+            .chain(self.sar.iter().flat_map(|sar| sar.bands()).map(|(_, m)| m))
+            .map(|m| m.total() * m.elem_size().unwrap_or(0))
+            .sum()
+    }
+
     fn load_image(path: &str) -> Mat {
         opencv::imgcodecs::imread(path, IMREAD_GRAYSCALE).unwrap()
     }
@@ -74,40 +355,65 @@ impl SatData {
         Err(Error::msg("Unable to find image!"))
     }
 
+    /// Tile metadata, if the product had a readable MTD_TL.xml
+    pub fn metadata(&self) -> Option<&TileMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Calibrated bands, if this is a Sentinel 1 product
+    pub fn sar(&self) -> Option<&SarBands> {
+        self.sar.as_ref()
+    }
+
+    /// Scene classification of a level 2A product, at 20 m (before scaling)
+    pub fn get_scl(&self) -> Option<Mat> {
+        self.scl.clone()
+    }
+
+    /// Get a band by its number, 1 to 12
+    pub fn get_band(&self, band: usize) -> Option<Mat> {
+        self.mat_array.get(band.checked_sub(1)?).map(narrow)
+    }
+
+    /// Get a band by its number as the product's 16 bit digital numbers, before any narrowing
+    pub fn get_raw_band(&self, band: usize) -> Option<Mat> {
+        self.mat_array.get(band.checked_sub(1)?).cloned()
+    }
+
     pub fn get_b1(&self) -> Mat {
-        self.mat_array[0].clone()
+        narrow(&self.mat_array[0])
     }
     pub fn get_b2(&self) -> Mat {
-        self.mat_array[1].clone()
+        narrow(&self.mat_array[1])
     }
     pub fn get_b3(&self) -> Mat {
-        self.mat_array[2].clone()
+        narrow(&self.mat_array[2])
     }
     pub fn get_b4(&self) -> Mat {
-        self.mat_array[3].clone()
+        narrow(&self.mat_array[3])
     }
     pub fn get_b5(&self) -> Mat {
-        self.mat_array[4].clone()
+        narrow(&self.mat_array[4])
     }
     pub fn get_b6(&self) -> Mat {
-        self.mat_array[5].clone()
+        narrow(&self.mat_array[5])
     }
     pub fn get_b7(&self) -> Mat {
-        self.mat_array[6].clone()
+        narrow(&self.mat_array[6])
     }
     pub fn get_b8(&self) -> Mat {
-        self.mat_array[7].clone()
+        narrow(&self.mat_array[7])
     }
     pub fn get_b9(&self) -> Mat {
-        self.mat_array[8].clone()
+        narrow(&self.mat_array[8])
     }
     pub fn get_b10(&self) -> Mat {
-        self.mat_array[9].clone()
+        narrow(&self.mat_array[9])
     }
     pub fn get_b11(&self) -> Mat {
-        self.mat_array[10].clone()
+        narrow(&self.mat_array[10])
     }
     pub fn get_b12(&self) -> Mat {
-        self.mat_array[11].clone()
+        narrow(&self.mat_array[11])
     }
 }
\ No newline at end of file
@@ -0,0 +1,461 @@
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error};
+use serde::Deserialize;
+
+use crate::encoding::Format;
+
+/// Everything the service can be configured with. Read from a TOML file (SETTINGS_FILE, or
+/// settings.toml when it exists) with environment variables on top, and checked once at startup.
+/// See settings.example.toml for the layout and the variable that overrides each setting
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub server: ServerSettings,
+    pub bucket: BucketSettings,
+    pub credentials: CredentialSettings,
+    pub cdse: CdseSettings,
+    pub render: RenderSettings,
+    pub jobs: JobSettings,
+    pub disk_cache: DiskCacheSettings,
+    pub memory_cache: MemoryCacheSettings,
+    pub precache: PrecacheSettings,
+    pub eviction: EvictionSettings,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub address: IpAddr,
+    pub port: u16,
+    /// Where clients reach us, for links we hand out. The Host header is used when unset
+    pub public_url: Option<String>,
+    /// Bearer token for the admin endpoints, which are off when unset
+    pub admin_token: Option<String>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> ServerSettings {
+        ServerSettings {
+            address: IpAddr::from([0, 0, 0, 0]),
+            port: 8000,
+            public_url: None,
+            admin_token: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BucketSettings {
+    /// The google bucket everything is kept in
+    pub name: String,
+}
+
+impl Default for BucketSettings {
+    fn default() -> BucketSettings {
+        BucketSettings {
+            name: "satellite-storage".to_string(),
+        }
+    }
+}
+
+/// Where the CDSE login comes from
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialProvider {
+    /// CDSE_USERNAME and CDSE_PASSWORD
+    Env,
+    /// A local key file, laid out like keys.toml
+    File,
+    /// A directory with cdse_username and cdse_password files, as secrets get mounted
+    SecretsDir,
+    /// A key file in the bucket
+    Bucket,
+}
+
+impl FromStr for CredentialProvider {
+    type Err = Error;
+
+    fn from_str(s: &str) -> anyhow::Result<CredentialProvider> {
+        match s {
+            "env" => Ok(CredentialProvider::Env),
+            "file" => Ok(CredentialProvider::File),
+            "secrets_dir" => Ok(CredentialProvider::SecretsDir),
+            "bucket" => Ok(CredentialProvider::Bucket),
+            _ => bail!("unknown credential provider, use env, file, secrets_dir or bucket"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialSettings {
+    pub provider: CredentialProvider,
+    /// The key file or secrets directory
+    pub path: Option<PathBuf>,
+    /// Key file in the bucket
+    pub object: String,
+}
+
+impl Default for CredentialSettings {
+    fn default() -> CredentialSettings {
+        CredentialSettings {
+            provider: CredentialProvider::Bucket,
+            path: None,
+            object: "keys.toml".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CdseSettings {
+    /// OData catalogue, for searching and downloading products
+    pub catalogue_url: String,
+    /// OpenID token endpoint
+    pub token_url: String,
+    pub client_id: String,
+    /// Times a request is retried after a 429, a 5xx or a network error before giving up
+    pub max_retries: u32,
+    /// First retry delay. It doubles on every retry, with jitter, up to retry_max_secs
+    pub retry_base_ms: u64,
+    pub retry_max_secs: u64,
+    /// Requests in flight at once to each endpoint. CDSE allows 4 concurrent downloads per user
+    pub token_requests: usize,
+    pub catalogue_requests: usize,
+    pub downloads: usize,
+    /// How often products ordered from the long term archive are checked on
+    pub order_poll_secs: u64,
+    /// Longest wait for a connection to CDSE, downloads included
+    pub connect_timeout_secs: u64,
+    /// Longest a catalogue or login request may take as a whole. Downloads have no overall limit
+    /// as zips are large
+    pub timeout_secs: u64,
+    /// Longest a download may wait for its answer or for the next part of the zip
+    pub read_timeout_secs: u64,
+}
+
+impl Default for CdseSettings {
+    fn default() -> CdseSettings {
+        CdseSettings {
+            catalogue_url: "https://catalogue.dataspace.copernicus.eu/odata/v1".to_string(),
+            token_url: "https://identity.dataspace.copernicus.eu/auth/realms/CDSE/protocol/openid-connect/token".to_string(),
+            client_id: "cdse-public".to_string(),
+            max_retries: 5,
+            retry_base_ms: 500,
+            retry_max_secs: 60,
+            token_requests: 2,
+            catalogue_requests: 4,
+            downloads: 4,
+            order_poll_secs: 600,
+            connect_timeout_secs: 10,
+            timeout_secs: 60,
+            read_timeout_secs: 60,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenderSettings {
+    /// Filter used when a request does not name one
+    pub default_filter: String,
+    /// Compression level of XZ payloads, 0 to 9
+    pub xz_level: u32,
+}
+
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            default_filter: "True Color".to_string(),
+            xz_level: 9,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobSettings {
+    /// Background jobs processed at once
+    pub workers: usize,
+}
+
+impl Default for JobSettings {
+    fn default() -> JobSettings {
+        JobSettings { workers: 2 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiskCacheSettings {
+    /// Local directory in front of the bucket, no disk cache when unset
+    pub dir: Option<PathBuf>,
+    pub max_mb: u64,
+    pub ttl_hours: u64,
+}
+
+impl Default for DiskCacheSettings {
+    fn default() -> DiskCacheSettings {
+        DiskCacheSettings {
+            dir: None,
+            max_mb: 20 * 1024,
+            ttl_hours: 72,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MemoryCacheSettings {
+    /// Budget for decoded products kept in memory, 0 turns it off
+    pub max_mb: usize,
+}
+
+impl Default for MemoryCacheSettings {
+    fn default() -> MemoryCacheSettings {
+        MemoryCacheSettings { max_mb: 4 * 1024 }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrecacheSettings {
+    /// Filters rendered ahead of time, none when empty
+    pub filters: Vec<String>,
+    /// As /v2/fetch takes them
+    pub formats: Vec<String>,
+    /// Largest side in pixels, or "full"
+    pub sizes: Vec<String>,
+    /// Store every overview level whenever a full size product is decoded
+    pub overviews: bool,
+    pub workers: usize,
+    /// Decoded products waiting to be precached. Each keeps its bands in memory until a worker gets
+    /// to it, a full size product takes a few GB
+    pub queue: usize,
+}
+
+impl Default for PrecacheSettings {
+    fn default() -> PrecacheSettings {
+        let list = |x: &[&str]| x.iter().map(|x| x.to_string()).collect();
+
+        PrecacheSettings {
+            filters: list(&["True Color", "False Color", "NDWI", "SWIR", "Red Edge"]),
+            formats: list(&["jpeg"]),
+            sizes: list(&["full"]),
+            overviews: true,
+            workers: 2,
+            queue: 4,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EvictionSettings {
+    /// Largest the bucket may get, no limit when unset
+    pub max_gb: Option<f64>,
+    /// Objects not read for this long are dropped, kept forever when unset
+    pub max_idle_days: Option<f64>,
+    /// Only ever drop product zips
+    pub zips_only: bool,
+    pub interval_hours: u64,
+}
+
+impl Default for EvictionSettings {
+    fn default() -> EvictionSettings {
+        EvictionSettings {
+            max_gb: None,
+            max_idle_days: None,
+            zips_only: false,
+            interval_hours: 24,
+        }
+    }
+}
+
+/// Replace a setting with an environment variable when it is set
+fn env<T: FromStr>(name: &str, setting: &mut T) -> anyhow::Result<()> where T::Err: Display {
+    if let Ok(value) = std::env::var(name) {
+        *setting = value.parse().map_err(|e| Error::msg(format!("{name}={value}: {e}")))?;
+    }
+
+    Ok(())
+}
+
+fn env_option<T: FromStr>(name: &str, setting: &mut Option<T>) -> anyhow::Result<()> where T::Err: Display {
+    if let Ok(value) = std::env::var(name) {
+        *setting = Some(value.parse().map_err(|e| Error::msg(format!("{name}={value}: {e}")))?);
+    }
+
+    Ok(())
+}
+
+/// Comma separated, "none" for an empty list
+fn env_list(name: &str, setting: &mut Vec<String>) {
+    if let Ok(value) = std::env::var(name) {
+        *setting = value.split(',')
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty() && !x.eq_ignore_ascii_case("none"))
+            .collect();
+    }
+}
+
+fn check_url(name: &str, url: &str) -> anyhow::Result<()> {
+    reqwest::Url::parse(url).with_context(|| format!("{name} is not a url: {url}"))?;
+
+    Ok(())
+}
+
+impl Settings {
+    /// Load, override from the environment and check the settings
+    pub fn load() -> anyhow::Result<Settings> {
+        let mut settings = match std::env::var("SETTINGS_FILE") {
+            Ok(path) => Settings::from_file(path.as_str())?,
+            Err(_) if std::path::Path::new("settings.toml").exists() => Settings::from_file("settings.toml")?,
+            Err(_) => Settings::default(),
+        };
+
+        settings.apply_env()?;
+        settings.validate()?;
+
+        Ok(settings)
+    }
+
+    fn from_file(path: &str) -> anyhow::Result<Settings> {
+        let contents = std::fs::read_to_string(path).with_context(|| format!("Could not read {path}"))?;
+
+        toml::from_str(contents.as_str()).with_context(|| format!("Could not parse {path}"))
+    }
+
+    fn apply_env(&mut self) -> anyhow::Result<()> {
+        env("ADDRESS", &mut self.server.address)?;
+        env("PORT", &mut self.server.port)?;
+        env_option("PUBLIC_URL", &mut self.server.public_url)?;
+        env_option("ADMIN_TOKEN", &mut self.server.admin_token)?;
+
+        env("BUCKET", &mut self.bucket.name)?;
+
+        env("CREDENTIALS_PROVIDER", &mut self.credentials.provider)?;
+        env_option("CREDENTIALS_PATH", &mut self.credentials.path)?;
+        env("KEYS_OBJECT", &mut self.credentials.object)?;
+
+        env("CDSE_CATALOGUE_URL", &mut self.cdse.catalogue_url)?;
+        env("CDSE_TOKEN_URL", &mut self.cdse.token_url)?;
+        env("CDSE_CLIENT_ID", &mut self.cdse.client_id)?;
+        env("CDSE_MAX_RETRIES", &mut self.cdse.max_retries)?;
+        env("CDSE_RETRY_BASE_MS", &mut self.cdse.retry_base_ms)?;
+        env("CDSE_RETRY_MAX_SECS", &mut self.cdse.retry_max_secs)?;
+        env("CDSE_TOKEN_REQUESTS", &mut self.cdse.token_requests)?;
+        env("CDSE_CATALOGUE_REQUESTS", &mut self.cdse.catalogue_requests)?;
+        env("CDSE_DOWNLOADS", &mut self.cdse.downloads)?;
+        env("CDSE_ORDER_POLL_SECS", &mut self.cdse.order_poll_secs)?;
+        env("CDSE_CONNECT_TIMEOUT_SECS", &mut self.cdse.connect_timeout_secs)?;
+        env("CDSE_TIMEOUT_SECS", &mut self.cdse.timeout_secs)?;
+        env("CDSE_READ_TIMEOUT_SECS", &mut self.cdse.read_timeout_secs)?;
+
+        env("DEFAULT_FILTER", &mut self.render.default_filter)?;
+        env("XZ_LEVEL", &mut self.render.xz_level)?;
+
+        env("JOB_WORKERS", &mut self.jobs.workers)?;
+
+        env_option("DISK_CACHE_DIR", &mut self.disk_cache.dir)?;
+        env("DISK_CACHE_MAX_MB", &mut self.disk_cache.max_mb)?;
+        env("DISK_CACHE_TTL_HOURS", &mut self.disk_cache.ttl_hours)?;
+
+        env("SAT_DATA_CACHE_MB", &mut self.memory_cache.max_mb)?;
+
+        env_list("PRECACHE_FILTERS", &mut self.precache.filters);
+        env_list("PRECACHE_FORMATS", &mut self.precache.formats);
+        env_list("PRECACHE_SIZES", &mut self.precache.sizes);
+        env("PRECACHE_OVERVIEWS", &mut self.precache.overviews)?;
+        env("PRECACHE_WORKERS", &mut self.precache.workers)?;
+        env("PRECACHE_QUEUE", &mut self.precache.queue)?;
+
+        env_option("CACHE_MAX_GB", &mut self.eviction.max_gb)?;
+        env_option("CACHE_MAX_IDLE_DAYS", &mut self.eviction.max_idle_days)?;
+        env("CACHE_EVICT_ZIPS_ONLY", &mut self.eviction.zips_only)?;
+        env("CACHE_EVICT_INTERVAL_HOURS", &mut self.eviction.interval_hours)?;
+
+        Ok(())
+    }
+
+    /// Catch bad settings at startup rather than on the first request that needs them
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some(public_url) = &self.server.public_url {
+            check_url("server.public_url", public_url)?;
+        }
+
+        if self.server.admin_token.as_ref().is_some_and(|x| x.is_empty()) {
+            bail!("server.admin_token is empty, leave it unset to turn the admin endpoints off");
+        }
+
+        if self.bucket.name.is_empty() {
+            bail!("bucket.name is needed");
+        }
+
+        match self.credentials.provider {
+            CredentialProvider::File | CredentialProvider::SecretsDir if self.credentials.path.is_none() => {
+                bail!("credentials.path is needed for the {:?} provider", self.credentials.provider)
+            }
+            CredentialProvider::Bucket if self.credentials.object.is_empty() => bail!("credentials.object is needed for the bucket provider"),
+            _ => {}
+        }
+
+        check_url("cdse.catalogue_url", self.cdse.catalogue_url.as_str())?;
+        check_url("cdse.token_url", self.cdse.token_url.as_str())?;
+
+        if self.cdse.token_requests == 0 || self.cdse.catalogue_requests == 0 || self.cdse.downloads == 0 {
+            bail!("cdse.token_requests, cdse.catalogue_requests and cdse.downloads need to be at least 1");
+        }
+
+        if self.cdse.order_poll_secs == 0 {
+            bail!("cdse.order_poll_secs needs to be at least 1");
+        }
+
+        if self.cdse.connect_timeout_secs == 0 || self.cdse.timeout_secs == 0 || self.cdse.read_timeout_secs == 0 {
+            bail!("cdse.connect_timeout_secs, cdse.timeout_secs and cdse.read_timeout_secs need to be at least 1");
+        }
+
+        if self.render.default_filter.is_empty() {
+            bail!("render.default_filter is empty");
+        }
+
+        if self.render.xz_level > 9 {
+            bail!("render.xz_level is {}, it goes from 0 to 9", self.render.xz_level);
+        }
+
+        if self.jobs.workers == 0 || self.precache.workers == 0 {
+            bail!("jobs.workers and precache.workers need to be at least 1");
+        }
+
+        if self.precache.queue == 0 {
+            bail!("precache.queue needs to be at least 1");
+        }
+
+        if self.disk_cache.dir.is_some() && self.disk_cache.max_mb == 0 {
+            bail!("disk_cache.max_mb is 0, leave disk_cache.dir unset to turn the disk cache off");
+        }
+
+        for format in &self.precache.formats {
+            Format::parse(format).with_context(|| format!("precache.formats: {format}"))?;
+        }
+
+        for size in self.precache.sizes.iter().filter(|x| *x != "full") {
+            size.parse::<u32>().with_context(|| format!("precache.sizes: {size} is not a number of pixels or \"full\""))?;
+        }
+
+        if self.eviction.max_gb.is_some_and(|x| x <= 0.0) || self.eviction.max_idle_days.is_some_and(|x| x <= 0.0) {
+            bail!("eviction.max_gb and eviction.max_idle_days need to be more than 0");
+        }
+
+        if self.eviction.interval_hours == 0 {
+            bail!("eviction.interval_hours needs to be at least 1");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,203 @@
+use std::f64::consts::PI;
+
+use opencv::core::{Scalar, Vector, BORDER_CONSTANT, CV_32F, CV_8U};
+use opencv::imgproc::{COLOR_GRAY2BGR, INTER_LINEAR};
+use opencv::prelude::{Mat, MatTraitConst, MatTraitManual};
+use opencv::types::VectorOfMat;
+use rocket::http::RawStr;
+
+use crate::filters::filter_version;
+use crate::geo::from_web_mercator;
+use crate::metadata::TileMetadata;
+use crate::sat_data::OVERVIEW_SCALES;
+
+/// Web map tiles are always 256 pixels across
+pub const TILE_SIZE: i32 = 256;
+
+/// Deepest zoom we render, a bit past the 10 m pixels of the bands
+pub const MAX_ZOOM: u32 = 18;
+
+/// Half the width of the web mercator world in meters
+const HALF_WORLD: f64 = PI * 6378137.0;
+
+/// Where a rendered tile is kept in the bucket. Like renders, tiles of an older filter version are
+/// never served
+pub fn tile_cache_name(id: &str, filter: &str, z: u32, x: u32, y: u32) -> String {
+    format!("{id}/tiles/{filter}/v{}/{z}/{x}/{y}.png", filter_version(filter))
+}
+
+/// The coarsest overview whose pixels are still no bigger than a tile pixel at this zoom (measured
+/// at the equator), so zoomed out tiles do not need the full resolution render
+pub fn overview_for_zoom(z: u32) -> u32 {
+    let pixel_meters = 2.0 * HALF_WORLD / ((1u64 << z) as f64 * TILE_SIZE as f64);
+
+    OVERVIEW_SCALES.iter()
+        .rev()
+        .find(|scale| 10.0 * **scale as f64 <= pixel_meters)
+        .copied()
+        .unwrap_or(1)
+}
+
+/// Check the tile exists at this zoom level
+pub fn valid_tile(z: u32, x: u32, y: u32) -> bool {
+    z <= MAX_ZOOM && x < 1 << z && y < 1 << z
+}
+
+/// Cut the XYZ tile out of a filter image covering the whole product tile, reprojecting it from
+/// the product's UTM grid to web mercator. Anything outside the product is transparent.
+pub fn render_tile(image: &Mat, metadata: &TileMetadata, z: u32, x: u32, y: u32) -> Mat {
+    let width = image.cols();
+    let height = image.rows();
+
+    let mut map_x = Mat::new_rows_cols_with_default(TILE_SIZE, TILE_SIZE, CV_32F, Scalar::all(-1.0)).unwrap();
+    let mut map_y = Mat::new_rows_cols_with_default(TILE_SIZE, TILE_SIZE, CV_32F, Scalar::all(-1.0)).unwrap();
+    let mut alpha = Mat::new_rows_cols_with_default(TILE_SIZE, TILE_SIZE, CV_8U, Scalar::all(0.0)).unwrap();
+
+    let tile_meters = 2.0 * HALF_WORLD / (1u64 << z) as f64;
+    let pixel_meters = tile_meters / TILE_SIZE as f64;
+
+    // find where the middle of every tile pixel lands on the image
+    for row in 0..TILE_SIZE {
+        for column in 0..TILE_SIZE {
+            let mercator_x = -HALF_WORLD + x as f64 * tile_meters + (column as f64 + 0.5) * pixel_meters;
+            let mercator_y = HALF_WORLD - y as f64 * tile_meters - (row as f64 + 0.5) * pixel_meters;
+
+            let (lon, lat) = from_web_mercator(mercator_x, mercator_y);
+
+            if let Some((image_x, image_y)) = metadata.to_pixel(lon, lat, width) {
+                if image_x >= 0.0 && image_y >= 0.0 && image_x < width as f64 && image_y < height as f64 {
+                    // remap samples at pixel centers
+                    *map_x.at_2d_mut::<f32>(row, column).unwrap() = (image_x - 0.5) as f32;
+                    *map_y.at_2d_mut::<f32>(row, column).unwrap() = (image_y - 0.5) as f32;
+                    *alpha.at_2d_mut::<u8>(row, column).unwrap() = 255;
+                }
+            }
+        }
+    }
+
+    // masks and other single band filters come out gray
+    let color = if image.channels() == 1 {
+        let mut m = Mat::default();
+        opencv::imgproc::cvt_color(image, &mut m, COLOR_GRAY2BGR, 0).unwrap();
+        m
+    } else {
+        image.clone()
+    };
+
+    let mut warped = Mat::default();
+    opencv::imgproc::remap(&color, &mut warped, &map_x, &map_y, INTER_LINEAR, BORDER_CONSTANT, Scalar::all(0.0)).unwrap();
+
+    let mut channels = VectorOfMat::new();
+    opencv::core::split(&warped, &mut channels).unwrap();
+    channels.push(alpha);
+
+    let mut tile = Mat::default();
+    opencv::core::merge(&channels, &mut tile).unwrap();
+
+    tile
+}
+
+/// Encode a tile as png
+pub fn encode_tile(tile: &Mat) -> Vec<u8> {
+    let mut buffer = Vector::new();
+    opencv::imgcodecs::imencode(".png", tile, &mut buffer, &Default::default()).unwrap();
+
+    buffer.to_vec()
+}
+
+/// Escape text for use in XML content or a quoted attribute
+fn xml_escape(text: &str) -> String {
+    let mut to_return = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => to_return.push_str("&amp;"),
+            '<' => to_return.push_str("&lt;"),
+            '>' => to_return.push_str("&gt;"),
+            '"' => to_return.push_str("&quot;"),
+            '\'' => to_return.push_str("&apos;"),
+            _ => to_return.push(c),
+        }
+    }
+
+    to_return
+}
+
+/// WMTS capabilities for one product and filter, using the standard Google Maps tile matrix set so
+/// clients can line it up with any other web map. base_url is where this service is reachable.
+pub fn capabilities(base_url: &str, id: &str, filter: &str, metadata: &TileMetadata) -> String {
+    // the product's corners in longitude and latitude
+    let width = metadata.columns as i32;
+    let corners: Vec<(f64, f64)> = [(0.0, 0.0), (width as f64, 0.0), (0.0, metadata.rows as f64), (width as f64, metadata.rows as f64)]
+        .iter()
+        .filter_map(|(column, row)| metadata.from_pixel(*column, *row, width))
+        .collect();
+
+    let min_lon = corners.iter().map(|c| c.0).fold(f64::MAX, f64::min);
+    let max_lon = corners.iter().map(|c| c.0).fold(f64::MIN, f64::max);
+    let min_lat = corners.iter().map(|c| c.1).fold(f64::MAX, f64::min);
+    let max_lat = corners.iter().map(|c| c.1).fold(f64::MIN, f64::max);
+
+    // the template is a url inside an attribute, so it is percent-encoded and then escaped
+    let template = xml_escape(format!(
+        "{base_url}/tiles/{}/{}/{{TileMatrix}}/{{TileCol}}/{{TileRow}}.png",
+        RawStr::new(id).percent_encode(),
+        RawStr::new(filter).percent_encode(),
+    ).as_str());
+
+    let title = xml_escape(format!("{filter} of {id}").as_str());
+    let layer = xml_escape(format!("{id}_{}", filter.replace(' ', "_")).as_str());
+
+    let mut tile_matrices = String::new();
+    for z in 0..=MAX_ZOOM {
+        let matrix_width = 1u64 << z;
+
+        // 0.28 mm per pixel is the standard rendering pixel size
+        let scale_denominator = 2.0 * HALF_WORLD / (matrix_width as f64 * TILE_SIZE as f64) / 0.00028;
+
+        tile_matrices.push_str(format!(
+            r#"
+      <TileMatrix>
+        <ows:Identifier>{z}</ows:Identifier>
+        <ScaleDenominator>{scale_denominator}</ScaleDenominator>
+        <TopLeftCorner>{} {}</TopLeftCorner>
+        <TileWidth>{TILE_SIZE}</TileWidth>
+        <TileHeight>{TILE_SIZE}</TileHeight>
+        <MatrixWidth>{matrix_width}</MatrixWidth>
+        <MatrixHeight>{matrix_width}</MatrixHeight>
+      </TileMatrix>"#, -HALF_WORLD, HALF_WORLD).as_str());
+    }
+
+    format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<Capabilities xmlns="http://www.opengis.net/wmts/1.0" xmlns:ows="http://www.opengis.net/ows/1.1" xmlns:xlink="http://www.w3.org/1999/xlink" version="1.0.0">
+  <ows:ServiceIdentification>
+    <ows:Title>Sentinel 2 tiles</ows:Title>
+    <ows:ServiceType>OGC WMTS</ows:ServiceType>
+    <ows:ServiceTypeVersion>1.0.0</ows:ServiceTypeVersion>
+  </ows:ServiceIdentification>
+  <Contents>
+    <Layer>
+      <ows:Title>{title}</ows:Title>
+      <ows:Identifier>{layer}</ows:Identifier>
+      <ows:WGS84BoundingBox>
+        <ows:LowerCorner>{min_lon} {min_lat}</ows:LowerCorner>
+        <ows:UpperCorner>{max_lon} {max_lat}</ows:UpperCorner>
+      </ows:WGS84BoundingBox>
+      <Style isDefault="true">
+        <ows:Identifier>default</ows:Identifier>
+      </Style>
+      <Format>image/png</Format>
+      <TileMatrixSetLink>
+        <TileMatrixSet>GoogleMapsCompatible</TileMatrixSet>
+      </TileMatrixSetLink>
+      <ResourceURL format="image/png" resourceType="tile" template="{template}"/>
+    </Layer>
+    <TileMatrixSet>
+      <ows:Identifier>GoogleMapsCompatible</ows:Identifier>
+      <ows:SupportedCRS>urn:ogc:def:crs:EPSG::3857</ows:SupportedCRS>
+      <WellKnownScaleSet>urn:ogc:def:wkss:OGC:1.0:GoogleMapsCompatible</WellKnownScaleSet>{tile_matrices}
+    </TileMatrixSet>
+  </Contents>
+</Capabilities>
+"#)
+}